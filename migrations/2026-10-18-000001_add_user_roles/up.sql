@@ -0,0 +1,5 @@
+-- Existing users could previously manage everything, so they become owners
+ALTER TABLE users
+    ADD COLUMN role VARCHAR(255) CHECK (role IN ('OWNER', 'EDITOR', 'VIEWER')) NOT NULL DEFAULT 'OWNER';
+ALTER TABLE users
+    ALTER COLUMN role DROP DEFAULT;
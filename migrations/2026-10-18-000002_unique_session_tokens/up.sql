@@ -0,0 +1,2 @@
+-- Bearer authentication looks up sessions by token alone
+CREATE UNIQUE INDEX sessions_token_key ON sessions (token);
@@ -0,0 +1,3 @@
+-- Digests can't be reversed, so existing sessions are invalidated
+DELETE FROM sessions;
+ALTER TABLE sessions RENAME COLUMN token_hash TO token;
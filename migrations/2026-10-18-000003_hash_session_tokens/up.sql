@@ -0,0 +1,3 @@
+-- Convert existing tokens to their base64 encoded SHA-256 digest
+ALTER TABLE sessions RENAME COLUMN token TO token_hash;
+UPDATE sessions SET token_hash = encode(sha256(convert_to(token_hash, 'UTF8')), 'base64');
@@ -0,0 +1,2 @@
+DROP TABLE password_reset_tokens;
+ALTER TABLE users ADD COLUMN password_reset_token VARCHAR(255) NULL;
@@ -0,0 +1,13 @@
+-- Existing reset links never expired, so they are invalidated rather than converted
+ALTER TABLE users DROP COLUMN password_reset_token;
+
+CREATE TABLE password_reset_tokens
+(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL,
+    token_hash VARCHAR(255) NOT NULL UNIQUE,
+    created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires TIMESTAMPTZ NOT NULL,
+    used TIMESTAMPTZ NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
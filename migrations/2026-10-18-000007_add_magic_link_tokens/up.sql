@@ -0,0 +1,10 @@
+CREATE TABLE magic_link_tokens
+(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL,
+    token_hash VARCHAR(255) NOT NULL UNIQUE,
+    created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires TIMESTAMPTZ NOT NULL,
+    used TIMESTAMPTZ NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
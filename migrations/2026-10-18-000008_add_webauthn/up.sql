@@ -0,0 +1,23 @@
+CREATE TABLE webauthn_credentials
+(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    credential_id VARCHAR(1024) NOT NULL UNIQUE, -- Base64 encoded
+    credential TEXT NOT NULL,                    -- JSON Serialized Credential
+    created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_used TIMESTAMPTZ NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+-- Server side state for an in progress registration or authentication
+CREATE TABLE webauthn_challenges
+(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL,
+    token_hash VARCHAR(255) NOT NULL UNIQUE,
+    kind VARCHAR(255) CHECK (kind IN ('REGISTER', 'AUTHENTICATE')) NOT NULL,
+    state TEXT NOT NULL,                         -- JSON Serialized State
+    created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
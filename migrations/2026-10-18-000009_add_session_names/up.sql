@@ -0,0 +1 @@
+ALTER TABLE sessions ADD COLUMN name VARCHAR(255) NULL;
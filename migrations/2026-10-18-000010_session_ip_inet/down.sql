@@ -0,0 +1,4 @@
+-- Sessions are invalidated rather than re-encoded as bincode
+DELETE FROM sessions;
+ALTER TABLE sessions DROP COLUMN last_ip;
+ALTER TABLE sessions ADD COLUMN last_ip BYTEA NOT NULL; -- Bincode Serialized IpAddr
@@ -0,0 +1,19 @@
+-- Decode the bincode serialized IpAddr: a 4 byte little endian variant tag (0 = V4, 1 = V6)
+-- followed by the 4 or 16 address octets
+ALTER TABLE sessions ADD COLUMN last_ip_inet INET NULL;
+
+UPDATE sessions
+SET last_ip_inet = CASE
+    WHEN length(last_ip) = 8 AND get_byte(last_ip, 0) = 0 THEN
+        (get_byte(last_ip, 4) || '.' || get_byte(last_ip, 5) || '.' ||
+         get_byte(last_ip, 6) || '.' || get_byte(last_ip, 7))::INET
+    WHEN length(last_ip) = 20 AND get_byte(last_ip, 0) = 1 THEN
+        regexp_replace(encode(substring(last_ip FROM 5 FOR 16), 'hex'), '(.{4})(?!$)', '\1:', 'g')::INET
+    END;
+
+-- Any rows which couldn't be decoded are unusable
+DELETE FROM sessions WHERE last_ip_inet IS NULL;
+
+ALTER TABLE sessions DROP COLUMN last_ip;
+ALTER TABLE sessions RENAME COLUMN last_ip_inet TO last_ip;
+ALTER TABLE sessions ALTER COLUMN last_ip SET NOT NULL;
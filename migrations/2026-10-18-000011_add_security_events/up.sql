@@ -0,0 +1,15 @@
+-- History of authentication related activity on each account
+CREATE TABLE security_events
+(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL,
+    kind VARCHAR(255) CHECK (kind IN ('LOGIN', 'LOGOUT', 'PASSWORD_CHANGED', 'PASSWORD_RESET_REQUESTED',
+                                      'PASSWORD_RESET', 'SESSION_REVOKED')) NOT NULL,
+    success BOOLEAN NOT NULL,
+    ip INET NULL,
+    user_agent VARCHAR(512) NULL,
+    created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX security_events_user_id_created_idx ON security_events (user_id, created);
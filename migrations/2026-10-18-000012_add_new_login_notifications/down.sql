@@ -0,0 +1,2 @@
+ALTER TABLE sessions DROP COLUMN revoke_token_hash;
+ALTER TABLE users DROP COLUMN notify_new_login;
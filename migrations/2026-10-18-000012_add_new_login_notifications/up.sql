@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN notify_new_login BOOLEAN DEFAULT TRUE NOT NULL;
+-- Allows a session to be revoked from the link in its new login email
+ALTER TABLE sessions ADD COLUMN revoke_token_hash VARCHAR(255) NULL UNIQUE;
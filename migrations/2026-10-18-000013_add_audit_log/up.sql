@@ -0,0 +1,15 @@
+-- Who changed what, rows are kept after the acting user is deleted
+CREATE TABLE audit_log
+(
+    id SERIAL PRIMARY KEY,
+    actor_id INT NULL,
+    action VARCHAR(255) CHECK (action IN ('CREATE', 'UPDATE', 'DELETE')) NOT NULL,
+    target_type VARCHAR(255) CHECK (target_type IN ('GALLERY_ITEM', 'USER')) NOT NULL,
+    target_id INT NOT NULL,
+    diff JSONB NOT NULL,                         -- {"field": {"before": .., "after": ..}}
+    created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (actor_id) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX audit_log_created_idx ON audit_log (created);
+CREATE INDEX audit_log_target_idx ON audit_log (target_type, target_id);
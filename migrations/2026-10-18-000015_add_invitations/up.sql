@@ -0,0 +1,14 @@
+-- Pending invitations, the user is only created once the invitation is accepted
+CREATE TABLE invitations
+(
+    id SERIAL PRIMARY KEY,
+    email VARCHAR(255) NOT NULL,
+    role VARCHAR(255) CHECK (role IN ('OWNER', 'EDITOR', 'VIEWER')) NOT NULL,
+    invited_by INT NULL,
+    token_hash VARCHAR(255) NOT NULL UNIQUE,
+    created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires TIMESTAMPTZ NOT NULL,
+    FOREIGN KEY (invited_by) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE UNIQUE INDEX invitations_email_key ON invitations (lower(email));
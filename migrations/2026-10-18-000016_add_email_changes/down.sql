@@ -0,0 +1,7 @@
+DELETE FROM security_events WHERE kind IN ('EMAIL_CHANGE_REQUESTED', 'EMAIL_CHANGED');
+ALTER TABLE security_events DROP CONSTRAINT security_events_kind_check;
+ALTER TABLE security_events ADD CONSTRAINT security_events_kind_check
+    CHECK (kind IN ('LOGIN', 'LOGOUT', 'PASSWORD_CHANGED', 'PASSWORD_RESET_REQUESTED',
+                    'PASSWORD_RESET', 'SESSION_REVOKED'));
+
+DROP TABLE email_changes;
@@ -0,0 +1,16 @@
+-- A new email address is only applied once the link sent to it has been followed
+CREATE TABLE email_changes
+(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL UNIQUE,
+    new_email VARCHAR(255) NOT NULL,
+    token_hash VARCHAR(255) NOT NULL UNIQUE,
+    created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires TIMESTAMPTZ NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+ALTER TABLE security_events DROP CONSTRAINT security_events_kind_check;
+ALTER TABLE security_events ADD CONSTRAINT security_events_kind_check
+    CHECK (kind IN ('LOGIN', 'LOGOUT', 'PASSWORD_CHANGED', 'PASSWORD_RESET_REQUESTED',
+                    'PASSWORD_RESET', 'SESSION_REVOKED', 'EMAIL_CHANGE_REQUESTED', 'EMAIL_CHANGED'));
@@ -0,0 +1,4 @@
+-- Emails are compared case insensitively, which the UNIQUE constraint didn't enforce.
+-- This fails if existing users have emails differing only by case, which need to be resolved first.
+CREATE UNIQUE INDEX users_email_lower_key ON users (lower(email));
+ALTER TABLE users DROP CONSTRAINT users_email_key;
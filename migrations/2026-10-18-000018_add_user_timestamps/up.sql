@@ -0,0 +1,7 @@
+-- Existing users are given the time of the migration as their creation time
+ALTER TABLE users ADD COLUMN created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL;
+ALTER TABLE users ADD COLUMN last_login TIMESTAMPTZ NULL;
+
+UPDATE users
+SET last_login = (SELECT max(created) FROM security_events e
+                  WHERE e.user_id = users.id AND e.kind = 'LOGIN' AND e.success);
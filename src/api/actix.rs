@@ -1,27 +1,54 @@
 use crate::api::errors::APIError;
-use actix_web::dev::ConnectionInfo;
+use actix_web::dev::ServiceRequest;
 use actix_web::http::header::HeaderMap;
+use actix_web::HttpRequest;
+use ipnetwork::IpNetwork;
 use std::net::{IpAddr, SocketAddr};
 
-pub trait ConnectionInfoExt {
-    fn ip_address(&self) -> Result<IpAddr, APIError>;
+pub trait ClientIpExt {
+    fn client_ip(&self, trusted_proxies: &[IpNetwork]) -> Result<IpAddr, APIError>;
 }
 
-impl ConnectionInfoExt for ConnectionInfo {
-    fn ip_address(&self) -> Result<IpAddr, APIError> {
-        let str = match self.remote() {
-            None => return Err(APIError::InternalError("remote() was none".to_string())),
-            Some(x) => x,
-        };
-        match str.parse::<SocketAddr>() {
-            Ok(x) => return Ok(x.ip()),
-            _ => {}
-        };
-        match str.parse::<IpAddr>() {
-            Ok(x) => return Ok(x),
-            _ => {}
-        };
-        Err(APIError::InternalError(format!("Couldn't parse {}", str)))
+// X-Forwarded-For can be set by anyone, so hops are only believed when they were added by a
+// trusted proxy. Starting from the connected peer, walk back through the header until the
+// first address that isn't a trusted proxy.
+fn resolve_client_ip(
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNetwork],
+) -> Result<IpAddr, APIError> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|n| n.contains(*ip));
+    let mut ip = match peer {
+        None => return Err(APIError::InternalError("peer_addr() was none".to_string())),
+        Some(x) => x.ip(),
+    };
+    let forwarded: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(|h| h.trim())
+        .collect();
+    for hop in forwarded.into_iter().rev() {
+        if !is_trusted(&ip) {
+            break;
+        }
+        match hop.parse::<IpAddr>() {
+            Ok(x) => ip = x,
+            Err(_) => break,
+        }
+    }
+    Ok(ip)
+}
+
+impl ClientIpExt for HttpRequest {
+    fn client_ip(&self, trusted_proxies: &[IpNetwork]) -> Result<IpAddr, APIError> {
+        resolve_client_ip(self.peer_addr(), self.headers(), trusted_proxies)
+    }
+}
+
+impl ClientIpExt for ServiceRequest {
+    fn client_ip(&self, trusted_proxies: &[IpNetwork]) -> Result<IpAddr, APIError> {
+        resolve_client_ip(self.peer_addr(), self.headers(), trusted_proxies)
     }
 }
 
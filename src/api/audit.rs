@@ -0,0 +1,156 @@
+use crate::api::auth::AuthenticatedUser;
+use crate::api::errors::APIError;
+use crate::models::NewAuditLogEntry;
+use crate::schema::audit_log::dsl as AL;
+use crate::state::Connection;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    Restore,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AuditTarget {
+    GalleryItem,
+    User,
+}
+
+impl FromStr for AuditAction {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_plain::from_str::<Self>(s).map_err(|_| ())
+    }
+}
+
+impl AuditAction {
+    pub fn serialize(&self) -> String {
+        serde_plain::to_string(&self).unwrap()
+    }
+}
+
+impl FromStr for AuditTarget {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_plain::from_str::<Self>(s).map_err(|_| ())
+    }
+}
+
+impl AuditTarget {
+    pub fn serialize(&self) -> String {
+        serde_plain::to_string(&self).unwrap()
+    }
+}
+
+fn to_object<T: Serialize>(value: Option<&T>) -> Result<Map<String, Value>, APIError> {
+    let value = value
+        .map(serde_json::to_value)
+        .transpose()
+        .map_err(|e| APIError::InternalError(format!("{}", e)))?;
+    match value {
+        Some(Value::Object(m)) => Ok(m),
+        Some(_) => Err(APIError::InternalError(
+            "Audited values must serialize to an object".to_owned(),
+        )),
+        None => Ok(Map::new()),
+    }
+}
+
+// Only the fields which differ are kept, a missing side (on create or delete) is null
+fn diff(before: Map<String, Value>, mut after: Map<String, Value>) -> Map<String, Value> {
+    let mut changes = Map::new();
+    for (key, old) in before {
+        let new = after.remove(&key).unwrap_or(Value::Null);
+        if old != new {
+            changes.insert(key, json!({ "before": old, "after": new }));
+        }
+    }
+    for (key, new) in after {
+        changes.insert(key, json!({ "before": Value::Null, "after": new }));
+    }
+    changes
+}
+
+// Records a change made by the authenticated user, the action is inferred from which snapshots
+// are given. This should be called within the same transaction as the change itself.
+pub fn record_change<T: Serialize>(
+    db: &Connection,
+    auth: &AuthenticatedUser,
+    target_type: AuditTarget,
+    target_id: i32,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> Result<(), APIError> {
+    let actor_id = Some(auth.user_id());
+    record_change_as(db, actor_id, target_type, target_id, before, after)
+}
+
+// As record_change, for changes made on behalf of a user outside of their session
+pub fn record_change_as<T: Serialize>(
+    db: &Connection,
+    actor_id: Option<i32>,
+    target_type: AuditTarget,
+    target_id: i32,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> Result<(), APIError> {
+    let action = match (before, after) {
+        (None, Some(_)) => AuditAction::Create,
+        (Some(_), Some(_)) => AuditAction::Update,
+        (Some(_), None) => AuditAction::Delete,
+        (None, None) => return Ok(()),
+    };
+    let changes = diff(to_object(before)?, to_object(after)?);
+    //Saving without changing anything isn't worth recording
+    if action == AuditAction::Update && changes.is_empty() {
+        return Ok(());
+    }
+    insert_entry(db, actor_id, action, target_type, target_id, changes)
+}
+
+// Records something which had been deleted being brought back
+pub fn record_restore<T: Serialize>(
+    db: &Connection,
+    auth: &AuthenticatedUser,
+    target_type: AuditTarget,
+    target_id: i32,
+    after: &T,
+) -> Result<(), APIError> {
+    let changes = diff(Map::new(), to_object(Some(after))?);
+    insert_entry(
+        db,
+        Some(auth.user_id()),
+        AuditAction::Restore,
+        target_type,
+        target_id,
+        changes,
+    )
+}
+
+fn insert_entry(
+    db: &Connection,
+    actor_id: Option<i32>,
+    action: AuditAction,
+    target_type: AuditTarget,
+    target_id: i32,
+    changes: Map<String, Value>,
+) -> Result<(), APIError> {
+    diesel::insert_into(AL::audit_log)
+        .values(&NewAuditLogEntry {
+            actor_id,
+            action: action.serialize(),
+            target_type: target_type.serialize(),
+            target_id,
+            diff: Value::Object(changes),
+        })
+        .execute(db)?;
+    Ok(())
+}
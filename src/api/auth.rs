@@ -1,62 +1,117 @@
-use crate::api::actix::{ConnectionInfoExt, HeaderMapExt};
+use crate::api::actix::{ClientIpExt, HeaderMapExt};
 use crate::api::errors::APIError;
+use crate::api::token::hash_token;
 use crate::models::{Session, User};
 use crate::state::AppState;
 use actix_web::dev::{Payload, ServiceRequest};
+use actix_web::http::header::AUTHORIZATION;
 use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
 use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use actix_web_httpauth::extractors::AuthExtractor;
 use diesel::prelude::*;
-use futures::future::{err, ok, Ready};
+use futures::future::{err, ok, LocalBoxFuture, Ready};
+use futures::{FutureExt, TryFutureExt};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+//Sessions can be authenticated using either Basic (user_id:token) or Bearer (token) schemes
+pub enum SessionCredentials {
+    Basic(BasicAuth),
+    Bearer(BearerAuth),
+}
+
+impl AuthExtractor for SessionCredentials {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_service_request(req: &ServiceRequest) -> Self::Future {
+        let is_bearer = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.starts_with("Bearer "))
+            .unwrap_or(false);
+        if is_bearer {
+            BearerAuth::from_service_request(req)
+                .map_ok(SessionCredentials::Bearer)
+                .err_into()
+                .boxed_local()
+        } else {
+            BasicAuth::from_service_request(req)
+                .map_ok(SessionCredentials::Basic)
+                .err_into()
+                .boxed_local()
+        }
+    }
+}
 
 pub async fn validator(
     req: ServiceRequest,
-    cred: BasicAuth,
+    cred: SessionCredentials,
 ) -> Result<ServiceRequest, actix_web::Error> {
     let state = req.app_data::<AppState>().expect("AppState missing");
-    let ip_addr = req.connection_info().ip_address();
+    let ip_addr = req.client_ip(&state.settings.app.trusted_proxies);
     let ua_opt = req.headers().user_agent();
 
     let result = web::block(move || {
         let ip_addr = ip_addr?;
-        let ip_bin = bincode::serialize(&ip_addr).unwrap();
+        let ip_net = IpNetwork::from(ip_addr);
         let user_agent = match ua_opt {
             Some(t) => t,
             None => return Err(APIError::BadAgent),
         };
-        let user: i32 = match cred.user_id().parse() {
-            Ok(val) => val,
-            Err(_) => return Err(APIError::MissingCredentials),
-        };
-        let pass = match cred.password() {
-            Some(val) => val,
-            None => return Err(APIError::MissingCredentials),
-        };
 
         use crate::schema::sessions::dsl as S;
         use crate::schema::users::dsl as U;
 
-        let db = state.new_connection();
-        let result: (Session, User) = match S::sessions
-            .filter(S::user_id.eq(user).and(S::token.eq(pass)))
+        let query = S::sessions
             .inner_join(U::users)
-            .first::<(Session, User)>(&db)
-        {
+            .filter(U::deleted_at.is_null())
+            .into_boxed();
+        let query = match &cred {
+            SessionCredentials::Basic(basic) => {
+                let user: i32 = match basic.user_id().parse() {
+                    Ok(val) => val,
+                    Err(_) => return Err(APIError::MissingCredentials),
+                };
+                let pass = match basic.password() {
+                    Some(val) => val,
+                    None => return Err(APIError::MissingCredentials),
+                };
+                query.filter(S::user_id.eq(user).and(S::token_hash.eq(hash_token(pass))))
+            }
+            SessionCredentials::Bearer(bearer) => {
+                query.filter(S::token_hash.eq(hash_token(bearer.token())))
+            }
+        };
+
+        let db = state.new_connection();
+        let result: (Session, User) = match query.first::<(Session, User)>(&db) {
             Ok(r) => r,
             Err(diesel::result::Error::NotFound) => return Err(APIError::IncorrectCredentials),
             Err(e) => return Err(e.into()),
         };
 
+        let expiry = &state.settings.sessions;
+        if result.0.last_used < expiry.idle_cutoff() || result.0.created < expiry.lifetime_cutoff()
+        {
+            diesel::delete(&result.0).execute(&db)?;
+            return Err(APIError::SessionExpired);
+        }
+
         //TODO: this could be moved onto a background thread
-        diesel::update(&result.0)
+        let session: Session = diesel::update(&result.0)
             .set((
                 S::user_agent.eq(user_agent),
-                S::last_ip.eq(ip_bin),
+                S::last_ip.eq(ip_net),
                 S::last_used.eq(diesel::dsl::now),
             ))
-            .execute(&db)?;
+            .get_result(&db)?;
 
         Ok(AuthenticatedUser {
-            session: result.0,
+            session,
             user: result.1,
         })
     })
@@ -81,6 +136,19 @@ impl AuthenticatedUser {
     pub fn user_id(&self) -> i32 {
         self.session.user_id
     }
+
+    pub fn role(&self) -> Role {
+        self.user.role.parse().unwrap()
+    }
+
+    //Returns Forbidden unless the user has at least the required role
+    pub fn require(&self, role: Role) -> Result<(), APIError> {
+        if self.role() >= role {
+            Ok(())
+        } else {
+            Err(APIError::Forbidden)
+        }
+    }
 }
 
 impl FromRequest for AuthenticatedUser {
@@ -93,7 +161,35 @@ impl FromRequest for AuthenticatedUser {
         if let Some(user) = req.extensions_mut().remove::<AuthenticatedUser>() {
             ok(user)
         } else {
-            err(APIError::InternalError("AuthenticatedUser not found in Request Extensions; use the Basic Authentication Middleware".to_owned()))
+            err(APIError::InternalError("AuthenticatedUser not found in Request Extensions; use the Authentication Middleware".to_owned()))
         }
     }
 }
+
+//Roles are ordered by increasing privilege
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role::Editor
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_plain::from_str::<Self>(s).map_err(|_| ())
+    }
+}
+
+impl Role {
+    pub fn serialize(&self) -> String {
+        serde_plain::to_string(&self).unwrap()
+    }
+}
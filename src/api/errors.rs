@@ -7,11 +7,24 @@ use serde::Serialize;
 struct APIErrorResponse {
     code: String,
     description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasons: Option<Vec<String>>,
 }
 
 impl APIErrorResponse {
     pub fn new(code: String, description: Option<String>) -> APIErrorResponse {
-        APIErrorResponse { code, description }
+        APIErrorResponse {
+            code,
+            description,
+            reasons: None,
+        }
+    }
+
+    pub fn with_reasons(self, reasons: Vec<String>) -> APIErrorResponse {
+        APIErrorResponse {
+            reasons: Some(reasons),
+            ..self
+        }
     }
 }
 
@@ -24,8 +37,11 @@ pub enum APIError {
     },
     BadAgent,
     ValidationError(String),
+    WeakPassword(Vec<String>),
     MissingCredentials,
     IncorrectCredentials,
+    SessionExpired,
+    AccountLocked,
     Forbidden,
     NotFound,
     MethodNotAllowed,
@@ -40,8 +56,11 @@ impl APIError {
             APIError::BadRequest { .. } => StatusCode::BAD_REQUEST,
             APIError::BadAgent { .. } => StatusCode::BAD_REQUEST,
             APIError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            APIError::WeakPassword(_) => StatusCode::BAD_REQUEST,
             APIError::MissingCredentials => StatusCode::UNAUTHORIZED,
             APIError::IncorrectCredentials => StatusCode::UNAUTHORIZED,
+            APIError::SessionExpired => StatusCode::UNAUTHORIZED,
+            APIError::AccountLocked => StatusCode::FORBIDDEN,
             APIError::Forbidden => StatusCode::FORBIDDEN,
             APIError::NotFound => StatusCode::NOT_FOUND,
             APIError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
@@ -60,12 +79,22 @@ impl APIError {
             APIError::ValidationError(s) => {
                 APIErrorResponse::new("VALIDATION_ERROR".to_owned(), Some(s.to_owned()))
             }
+            APIError::WeakPassword(reasons) => APIErrorResponse::new(
+                "WEAK_PASSWORD".to_owned(),
+                Some("The password does not meet the password policy".to_owned()),
+            )
+            .with_reasons(reasons.to_owned()),
             APIError::MissingCredentials => {
                 APIErrorResponse::new("MISSING_CREDENTIALS".to_owned(), None)
             }
             APIError::IncorrectCredentials => {
                 APIErrorResponse::new("INCORRECT_CREDENTIALS".to_owned(), None)
             }
+            APIError::SessionExpired => APIErrorResponse::new("SESSION_EXPIRED".to_owned(), None),
+            APIError::AccountLocked => APIErrorResponse::new(
+                "ACCOUNT_LOCKED".to_owned(),
+                Some("Too many failed attempts, reset your password to unlock".to_owned()),
+            ),
             APIError::Forbidden => APIErrorResponse::new("FORBIDDEN".to_owned(), None),
             APIError::NotFound => APIErrorResponse::new("NOT_FOUND".to_owned(), None),
             APIError::MethodNotAllowed => {
@@ -97,12 +126,34 @@ impl ResponseError for APIError {
     }
 }
 
+const USERS_EMAIL_INDEX: &str = "users_email_lower_key";
+const INVITATIONS_EMAIL_INDEX: &str = "invitations_email_key";
+
 //Note that not finding an expected row will be transformed into an APIError::NotFound
 impl From<diesel::result::Error> for APIError {
     fn from(err: diesel::result::Error) -> Self {
-        use diesel::result::Error;
+        use diesel::result::{DatabaseErrorKind, Error};
         match err {
             Error::NotFound => APIError::NotFound,
+            //Emails are checked before use, but this catches concurrent requests
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, ref info)
+                if info.constraint_name() == Some(USERS_EMAIL_INDEX) =>
+            {
+                APIError::BadRequest {
+                    code: "EMAIL_TAKEN".to_owned(),
+                    description: Some("The Email address is already in use".to_string()),
+                }
+            }
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, ref info)
+                if info.constraint_name() == Some(INVITATIONS_EMAIL_INDEX) =>
+            {
+                APIError::BadRequest {
+                    code: "ALREADY_INVITED".to_owned(),
+                    description: Some(
+                        "An invitation has already been sent to this email".to_string(),
+                    ),
+                }
+            }
             err => APIError::InternalError(format!("{}", err)),
         }
     }
@@ -132,6 +183,12 @@ impl From<actix_web::error::UrlencodedError> for APIError {
     }
 }
 
+impl From<actix_web::error::JsonPayloadError> for APIError {
+    fn from(err: actix_web::error::JsonPayloadError) -> Self {
+        APIError::ValidationError(format!("{}", err))
+    }
+}
+
 impl From<actix_web::error::QueryPayloadError> for APIError {
     fn from(err: actix_web::error::QueryPayloadError) -> Self {
         APIError::ValidationError(format!("{}", err))
@@ -169,6 +226,22 @@ impl From<bcrypt::BcryptError> for APIError {
     }
 }
 
+impl From<argon2::Error> for APIError {
+    fn from(err: argon2::Error) -> Self {
+        APIError::InternalError(format!("{}", err))
+    }
+}
+
+impl From<webauthn_rs::error::WebauthnError> for APIError {
+    fn from(err: webauthn_rs::error::WebauthnError) -> Self {
+        log::info!("WebAuthn ceremony failed: {:?}", err);
+        APIError::BadRequest {
+            code: "WEBAUTHN_FAILED".to_owned(),
+            description: Some("The passkey could not be verified".to_string()),
+        }
+    }
+}
+
 impl From<lettre::smtp::error::Error> for APIError {
     fn from(err: lettre::smtp::error::Error) -> Self {
         APIError::InternalError(format!("{}", err))
@@ -1,19 +1,27 @@
 mod actix;
+mod audit;
 mod auth;
 mod errors;
 mod files;
+mod password;
 mod routes;
+mod security_events;
 mod token;
+mod totp;
+mod webauthn;
 
+use crate::api::actix::ClientIpExt;
 use crate::api::errors::APIError;
 use crate::state::AppState;
 use actix_files::Files;
+use actix_ratelimit::errors::ARError;
 use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
 use actix_validated_forms::form::ValidatedFormConfig;
 use actix_validated_forms::multipart::ValidatedMultipartFormConfig;
 use actix_validated_forms::query::ValidatedQueryConfig;
+use actix_web::dev::ServiceRequest;
 use actix_web::error::ResponseError;
-use actix_web::web::{self, Data, PathConfig};
+use actix_web::web::{self, Data, JsonConfig, PathConfig};
 use actix_web::HttpResponse;
 use actix_web_httpauth::middleware::HttpAuthentication;
 use serde::Serialize;
@@ -30,16 +38,42 @@ fn resource(path: &str) -> actix_web::Resource {
         .default_service(web::route().to(|| APIError::MethodNotAllowed.error_response()))
 }
 
+//Rate limit by the same client IP that is recorded against sessions
+fn client_ip_identifier(
+    state: &AppState,
+) -> impl Fn(&ServiceRequest) -> Result<String, ARError> + 'static {
+    let settings = state.settings.clone();
+    move |req| {
+        req.client_ip(&settings.app.trusted_proxies)
+            .map(|ip| ip.to_string())
+            .map_err(|_| ARError::IdentificationError)
+    }
+}
+
+//Limits how often each client IP can make requests, counting them in the shared store
+fn ip_rate_limiter(
+    state: &AppState,
+    store: &MemoryStore,
+    interval: Duration,
+    max_requests: usize,
+) -> RateLimiter<MemoryStoreActor> {
+    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+        .with_identifier(client_ip_identifier(state))
+        .with_interval(interval)
+        .with_max_requests(max_requests)
+}
+
 async fn index(_state: Data<AppState>) -> String {
     format!("Kiwi API")
 }
 
 pub fn configure(cfg: &mut web::ServiceConfig, state: AppState) {
-    let auth_mw = HttpAuthentication::basic(auth::validator);
+    let auth_mw = HttpAuthentication::with_fn(auth::validator);
     let rl_store = MemoryStore::new();
     cfg.service(
         scope("/")
             .app_data(PathConfig::default().error_handler(|e, _| APIError::from(e).into()))
+            .app_data(JsonConfig::default().error_handler(|e, _| APIError::from(e).into()))
             .app_data(ValidatedFormConfig::default().error_handler(|e, _| APIError::from(e).into()))
             .app_data(
                 ValidatedQueryConfig::default().error_handler(|e, _| APIError::from(e).into()),
@@ -54,11 +88,82 @@ pub fn configure(cfg: &mut web::ServiceConfig, state: AppState) {
                     .service(
                         resource("login")
                             .route(web::post().to(routes::session::password_login))
-                            .wrap(
-                                RateLimiter::new(MemoryStoreActor::from(rl_store.clone()).start())
-                                    .with_interval(Duration::from_secs(120))
-                                    .with_max_requests(5),
-                            ),
+                            .wrap(ip_rate_limiter(
+                                &state,
+                                &rl_store,
+                                Duration::from_secs(120),
+                                5,
+                            )),
+                    )
+                    .service(
+                        resource("login/totp")
+                            .route(web::post().to(routes::session::totp_login))
+                            .wrap(ip_rate_limiter(
+                                &state,
+                                &rl_store,
+                                Duration::from_secs(120),
+                                5,
+                            )),
+                    )
+                    .service(
+                        resource("magic")
+                            .route(web::post().to(routes::magic_link::login))
+                            .wrap(ip_rate_limiter(
+                                &state,
+                                &rl_store,
+                                Duration::from_secs(120),
+                                5,
+                            )),
+                    )
+                    .service(
+                        resource("magic/request")
+                            .route(web::post().to(routes::magic_link::request))
+                            .wrap(ip_rate_limiter(
+                                &state,
+                                &rl_store,
+                                Duration::from_secs(120),
+                                3,
+                            )),
+                    )
+                    .service(
+                        resource("webauthn/register/start")
+                            .route(web::post().to(routes::webauthn::register_start))
+                            .wrap(auth_mw.clone()),
+                    )
+                    .service(
+                        resource("webauthn/register/finish")
+                            .route(web::post().to(routes::webauthn::register_finish))
+                            .wrap(auth_mw.clone()),
+                    )
+                    .service(
+                        resource("webauthn/login/start")
+                            .route(web::post().to(routes::webauthn::login_start))
+                            .wrap(ip_rate_limiter(
+                                &state,
+                                &rl_store,
+                                Duration::from_secs(120),
+                                5,
+                            )),
+                    )
+                    .service(
+                        resource("webauthn/login/finish")
+                            .route(web::post().to(routes::webauthn::login_finish))
+                            .wrap(ip_rate_limiter(
+                                &state,
+                                &rl_store,
+                                Duration::from_secs(120),
+                                5,
+                            )),
+                    )
+                    .service(
+                        resource("webauthn/credentials")
+                            .route(web::get().to(routes::webauthn::list_credentials))
+                            .wrap(auth_mw.clone()),
+                    )
+                    .service(
+                        resource("webauthn/credentials/{credential_id}")
+                            .route(web::delete().to(routes::webauthn::delete_credential))
+                            .wrap(auth_mw.clone()),
                     )
                     .service(
                         resource("logout")
@@ -70,35 +175,120 @@ pub fn configure(cfg: &mut web::ServiceConfig, state: AppState) {
                             .route(web::get().to(routes::session::list))
                             .wrap(auth_mw.clone()),
                     )
+                    .service(
+                        resource("others")
+                            .route(web::delete().to(routes::session::delete_others))
+                            .wrap(auth_mw.clone()),
+                    )
+                    .service(
+                        resource("revoke")
+                            .route(web::post().to(routes::session::revoke))
+                            .wrap(ip_rate_limiter(
+                                &state,
+                                &rl_store,
+                                Duration::from_secs(120),
+                                5,
+                            )),
+                    )
                     .service(
                         resource("{session_id}")
+                            .route(web::put().to(routes::session::update))
                             .route(web::delete().to(routes::session::delete))
                             .wrap(auth_mw.clone()),
                     ),
             )
             .service(
                 scope("users")
-                    .service(
-                        resource("")
-                            .route(web::get().to(routes::users::list))
-                            .route(web::post().to(routes::users::create)),
-                    )
+                    .service(resource("").route(web::get().to(routes::users::list)))
                     .service(
                         resource("{user_id}")
                             .route(web::get().to(routes::users::get))
                             .route(web::put().to(routes::users::update))
                             .route(web::delete().to(routes::users::delete)),
                     )
+                    .service(
+                        resource("{user_id}/restore").route(web::post().to(routes::users::restore)),
+                    )
+                    .service(
+                        resource("{user_id}/sessions")
+                            .route(web::delete().to(routes::users::delete_sessions)),
+                    )
+                    .service(
+                        resource("{user_id}/security_events")
+                            .route(web::get().to(routes::users::security_events)),
+                    )
+                    .service(
+                        resource("{user_id}/password")
+                            .route(web::put().to(routes::users::change_password)),
+                    )
+                    .service(
+                        resource("{user_id}/totp")
+                            .route(web::post().to(routes::totp::enrol))
+                            .route(web::delete().to(routes::totp::disable)),
+                    )
+                    .service(
+                        resource("{user_id}/totp/confirm")
+                            .route(web::post().to(routes::totp::confirm)),
+                    )
+                    .service(
+                        resource("{user_id}/totp/recovery_codes")
+                            .route(web::post().to(routes::totp::regenerate_recovery_codes)),
+                    )
+                    .wrap(auth_mw.clone()),
+            )
+            .service(
+                resource("email_change/confirm")
+                    .route(web::post().to(routes::email_change::confirm))
+                    .wrap(ip_rate_limiter(
+                        &state,
+                        &rl_store,
+                        Duration::from_secs(120),
+                        5,
+                    )),
+            )
+            .service(
+                scope("invitations")
+                    .service(
+                        resource("accept")
+                            .route(web::post().to(routes::invitations::accept))
+                            .wrap(ip_rate_limiter(
+                                &state,
+                                &rl_store,
+                                Duration::from_secs(120),
+                                5,
+                            )),
+                    )
+                    .service(
+                        resource("")
+                            .route(web::get().to(routes::invitations::list))
+                            .route(web::post().to(routes::invitations::create))
+                            .wrap(auth_mw.clone()),
+                    )
+                    .service(
+                        resource("{invitation_id}")
+                            .route(web::delete().to(routes::invitations::revoke))
+                            .wrap(auth_mw.clone()),
+                    )
+                    .service(
+                        resource("{invitation_id}/resend")
+                            .route(web::post().to(routes::invitations::resend))
+                            .wrap(auth_mw.clone()),
+                    ),
+            )
+            .service(
+                resource("audit_log")
+                    .route(web::get().to(routes::audit_log::list))
                     .wrap(auth_mw.clone()),
             )
             .service(
                 resource("contact")
                     .route(web::post().to(routes::contact::contact_form))
-                    .wrap(
-                        RateLimiter::new(MemoryStoreActor::from(rl_store.clone()).start())
-                            .with_interval(Duration::from_secs(120))
-                            .with_max_requests(3),
-                    ),
+                    .wrap(ip_rate_limiter(
+                        &state,
+                        &rl_store,
+                        Duration::from_secs(120),
+                        3,
+                    )),
             )
             .service(
                 scope("password_reset")
@@ -108,11 +298,12 @@ pub fn configure(cfg: &mut web::ServiceConfig, state: AppState) {
                     .service(
                         resource("submit").route(web::post().to(routes::password_reset::submit)),
                     )
-                    .wrap(
-                        RateLimiter::new(MemoryStoreActor::from(rl_store.clone()).start())
-                            .with_interval(Duration::from_secs(120))
-                            .with_max_requests(3),
-                    ),
+                    .wrap(ip_rate_limiter(
+                        &state,
+                        &rl_store,
+                        Duration::from_secs(120),
+                        3,
+                    )),
             )
             .service(
                 scope("gallery")
@@ -0,0 +1,124 @@
+use crate::api::errors::APIError;
+use crate::settings::{PasswordHashing, PasswordPolicy};
+use argon2::{Config, ThreadMode, Variant, Version};
+use rand::distributions::Standard;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::io::{BufRead, BufReader};
+
+const SALT_BYTES: usize = 16;
+const HASH_BYTES: u32 = 32;
+
+fn argon2_config(params: &PasswordHashing) -> Config {
+    Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: params.memory_kib,
+        time_cost: params.iterations,
+        lanes: params.parallelism,
+        thread_mode: ThreadMode::Sequential,
+        secret: &[],
+        ad: &[],
+        hash_length: HASH_BYTES,
+    }
+}
+
+// New passwords are always hashed with Argon2id, encoded as a PHC string
+pub fn hash_password(params: &PasswordHashing, password: &str) -> Result<String, APIError> {
+    let rng = rand::thread_rng();
+    let salt: Vec<u8> = rng.sample_iter(&Standard).take(SALT_BYTES).collect();
+    Ok(argon2::hash_encoded(
+        password.as_bytes(),
+        &salt,
+        &argon2_config(params),
+    )?)
+}
+
+// Supports both Argon2 PHC strings and legacy bcrypt hashes
+pub fn verify_password(hashed: &str, password: &str) -> Result<bool, APIError> {
+    if hashed.starts_with("$argon2") {
+        Ok(argon2::verify_encoded(hashed, password.as_bytes())?)
+    } else {
+        Ok(bcrypt::verify(password, hashed)?)
+    }
+}
+
+// Whether a hash uses an outdated algorithm or parameters, and should be replaced
+pub fn needs_rehash(params: &PasswordHashing, hashed: &str) -> bool {
+    // $argon2id$v=19$m=...,t=...,p=...$salt$hash
+    let mut parts = hashed.split('$').skip(1);
+    if parts.next() != Some("argon2id") || parts.next() != Some("v=19") {
+        return true;
+    }
+    let expected = format!(
+        "m={},t={},p={}",
+        params.memory_kib, params.iterations, params.parallelism
+    );
+    parts.next() != Some(expected.as_str())
+}
+
+// Returns a WEAK_PASSWORD error listing a code for every rule the password breaks, the limits
+// themselves come from the settings so clients are expected to know them
+pub fn check_password_policy(
+    policy: &PasswordPolicy,
+    password: &str,
+    name: &str,
+    email: &str,
+) -> Result<(), APIError> {
+    let mut reasons = Vec::new();
+    let length = password.chars().count();
+    if length < policy.min_length {
+        reasons.push("TOO_SHORT");
+    }
+    if length > policy.max_length {
+        reasons.push("TOO_LONG");
+    }
+    if policy.disallow_personal_info {
+        let lower = password.to_lowercase();
+        let local_part = email.split('@').next().unwrap_or("").to_lowercase();
+        if local_part.len() >= 3 && lower.contains(&local_part) {
+            reasons.push("CONTAINS_EMAIL");
+        }
+        let name: String = name.split_whitespace().collect();
+        let name = name.to_lowercase();
+        if name.len() >= 3 && lower.replace(char::is_whitespace, "").contains(&name) {
+            reasons.push("CONTAINS_NAME");
+        }
+    }
+    if is_breached(policy, password) {
+        reasons.push("BREACHED");
+    }
+    if reasons.is_empty() {
+        Ok(())
+    } else {
+        Err(APIError::WeakPassword(
+            reasons.into_iter().map(str::to_owned).collect(),
+        ))
+    }
+}
+
+// The folder uses the k-anonymity layout, a file for each 5 character SHA-1 prefix
+// containing lines of `SUFFIX:COUNT`
+fn is_breached(policy: &PasswordPolicy, password: &str) -> bool {
+    let folder = match &policy.breached_passwords_folder {
+        Some(f) => f,
+        None => return false,
+    };
+    let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(5);
+    let file = match std::fs::File::open(folder.join(prefix)) {
+        Ok(f) => f,
+        Err(e) => {
+            log::warn!("Unable to open breached passwords file {}: {}", prefix, e);
+            return false;
+        }
+    };
+    BufReader::new(file)
+        .lines()
+        .filter_map(|l| l.ok())
+        //Some lists use lowercase hex
+        .any(|l| {
+            let hash = l.split(':').next().unwrap_or("");
+            hash.trim().eq_ignore_ascii_case(suffix)
+        })
+}
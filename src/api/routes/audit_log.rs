@@ -0,0 +1,137 @@
+use crate::api::audit::{AuditAction, AuditTarget};
+use crate::api::auth::{AuthenticatedUser, Role};
+use crate::api::errors::APIError;
+use crate::api::ok_json;
+use crate::ext::postgres::cursor::{
+    keyset_limit, keyset_order, Cursor, CursorResult, PageResult, TimestampKey,
+};
+use crate::ext::postgres::limit::{CountedLimitResult, CountingLimit};
+use crate::models::AuditLogEntry;
+use crate::schema::audit_log::dsl as AL;
+use crate::schema::audit_log::BoxedQuery;
+use crate::state::AppState;
+use actix_validated_forms::query::ValidatedQuery;
+use actix_web::web::Data;
+use actix_web::{web, HttpResponse};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use futures::TryFutureExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use validator::Validate;
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct ListAuditLogQuery {
+    #[validate(range(min = 1, max = 100))]
+    limit: i64,
+    offset: i64,
+    //Cursor pagination is used when asked for, or when continuing from a cursor
+    use_cursor: bool,
+    cursor: Option<Cursor<(TimestampKey, i32)>>,
+    include_total: bool,
+    actor_id: Option<i32>,
+    action: Option<AuditAction>,
+    target_type: Option<AuditTarget>,
+    target_id: Option<i32>,
+}
+
+impl Default for ListAuditLogQuery {
+    fn default() -> Self {
+        ListAuditLogQuery {
+            limit: 20,
+            offset: 0,
+            use_cursor: false,
+            cursor: None,
+            include_total: true,
+            actor_id: None,
+            action: None,
+            target_type: None,
+            target_id: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditLogResponseItem {
+    id: i32,
+    actor_id: Option<i32>,
+    action: AuditAction,
+    target_type: AuditTarget,
+    target_id: i32,
+    diff: Value,
+    created: i64,
+}
+
+impl From<AuditLogEntry> for AuditLogResponseItem {
+    fn from(e: AuditLogEntry) -> Self {
+        AuditLogResponseItem {
+            id: e.id,
+            actor_id: e.actor_id,
+            action: e.action.parse().unwrap(),
+            target_type: e.target_type.parse().unwrap(),
+            target_id: e.target_id,
+            diff: e.diff,
+            created: e.created.timestamp(),
+        }
+    }
+}
+
+fn filtered_entries(query: &ListAuditLogQuery) -> BoxedQuery<'static, Pg> {
+    let mut q = AL::audit_log.into_boxed();
+    if let Some(actor_id) = query.actor_id {
+        q = q.filter(AL::actor_id.eq(actor_id));
+    }
+    if let Some(action) = &query.action {
+        q = q.filter(AL::action.eq(action.serialize()));
+    }
+    if let Some(target_type) = &query.target_type {
+        q = q.filter(AL::target_type.eq(target_type.serialize()));
+    }
+    if let Some(target_id) = query.target_id {
+        q = q.filter(AL::target_id.eq(target_id));
+    }
+    q
+}
+
+//Newest first, the log can be paged by cursor instead of offset as it grows
+pub async fn list(
+    auth: AuthenticatedUser,
+    query: ValidatedQuery<ListAuditLogQuery>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    auth.require(Role::Owner)?;
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+
+        let result = if query.use_cursor || query.cursor.is_some() {
+            let result: CursorResult<AuditLogEntry> =
+                keyset_limit(query.cursor.clone(), query.limit)
+                    .with_total(query.include_total)
+                    .load(
+                        |e: &AuditLogEntry| (TimestampKey(e.created), e.id),
+                        |key, direction, limit| {
+                            let q = filtered_entries(&query);
+                            let key = key.map(|&(TimestampKey(t), id)| (t, id));
+                            keyset_order(q, AL::created, AL::id, false, key, direction)
+                                .limit(limit)
+                                .load::<AuditLogEntry>(&db)
+                        },
+                        || filtered_entries(&query).count().get_result::<i64>(&db),
+                    )?;
+            PageResult::Cursor(result)
+        } else {
+            let result: CountedLimitResult<AuditLogEntry> = filtered_entries(&query)
+                .order((AL::created.desc(), AL::id.desc()))
+                .counted_limit(query.limit)
+                .offset(query.offset)
+                .load_with_total::<AuditLogEntry>(&db)?;
+            PageResult::Counted(result)
+        };
+
+        Ok(result.map(AuditLogResponseItem::from))
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
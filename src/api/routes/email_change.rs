@@ -0,0 +1,188 @@
+use crate::api::audit::{record_change_as, AuditTarget};
+use crate::api::errors::APIError;
+use crate::api::ok_json;
+use crate::api::routes::session::AUTH_TOKEN_BYTES;
+use crate::api::routes::users::{assert_email_available, UserResponseItem};
+use crate::api::security_events::{record_event, EventSource, SecurityEventKind};
+use crate::api::token::{generate_token, hash_token};
+use crate::models::{EmailChange, User};
+use crate::schema::email_changes::dsl as EC;
+use crate::schema::users::dsl as U;
+use crate::settings::Settings;
+use crate::state::{AppState, Connection};
+use actix_validated_forms::form::ValidatedForm;
+use actix_web::web::Data;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use futures::TryFutureExt;
+use lettre::Transport;
+use lettre_email::EmailBuilder;
+use serde::Deserialize;
+use url::Url;
+use validator::Validate;
+
+fn confirm_url(settings: &Settings) -> Result<Url, APIError> {
+    match &settings.email_change.url {
+        Some(u) => Ok(u.clone()),
+        None => Err(APIError::NotImplemented),
+    }
+}
+
+fn send_confirmation_email(settings: &Settings, email: &str, token: &str) -> Result<(), APIError> {
+    let mut mailer = settings.mailer.smtp_transport()?;
+
+    let mut url = confirm_url(settings)?;
+    url.query_pairs_mut().append_pair("token", token);
+    let body = format!(
+        "Confirm this as the email address for your Kiwi Admin account using the link below:\
+        \n\n{}\n\nThis link expires in {} hours.",
+        url, settings.email_change.expiry_hours
+    );
+
+    let email = EmailBuilder::new()
+        .to(email)
+        .from(settings.mailer.get_from_address())
+        .reply_to("noreply@kiwijoinerydevon.co.uk")
+        .subject("Kiwi Website Confirm Email Address")
+        .body(body)
+        .build()
+        .unwrap();
+    mailer.send(email.into())?;
+    Ok(())
+}
+
+//Sent to the current address, in case the change wasn't made by the account holder
+fn send_change_notice_email(
+    settings: &Settings,
+    email: &str,
+    new_email: &str,
+) -> Result<(), APIError> {
+    let mut mailer = settings.mailer.smtp_transport()?;
+
+    let body = format!(
+        "A request was made to change the email address of your Kiwi Admin account to {}. \
+        The change will be applied once it has been confirmed from the new address.\n\n\
+        If this wasn't you then change your password, and log out any sessions you don't \
+        recognise.",
+        new_email
+    );
+
+    let email = EmailBuilder::new()
+        .to(email)
+        .from(settings.mailer.get_from_address())
+        .reply_to("noreply@kiwijoinerydevon.co.uk")
+        .subject("Kiwi Website Email Address Change")
+        .body(body)
+        .build()
+        .unwrap();
+    mailer.send(email.into())?;
+    Ok(())
+}
+
+//Replaces any pending change for the user, the email address is only updated once confirmed.
+//Returns the token, which should be sent with send_email_change once the change is committed
+pub fn request_email_change(
+    db: &Connection,
+    settings: &Settings,
+    user: &User,
+    new_email: &str,
+    source: &EventSource,
+) -> Result<String, APIError> {
+    confirm_url(settings)?;
+    assert_email_available(db, new_email)?;
+
+    let token = generate_token(AUTH_TOKEN_BYTES);
+    diesel::delete(EmailChange::belonging_to(user)).execute(db)?;
+    diesel::insert_into(EC::email_changes)
+        .values((
+            EC::user_id.eq(user.id),
+            EC::new_email.eq(new_email),
+            EC::token_hash.eq(hash_token(&token)),
+            EC::expires.eq(Utc::now() + Duration::hours(settings.email_change.expiry_hours)),
+        ))
+        .execute(db)?;
+    record_event(
+        db,
+        user.id,
+        SecurityEventKind::EmailChangeRequested,
+        true,
+        source,
+    )?;
+    Ok(token)
+}
+
+pub fn send_email_change(
+    settings: &Settings,
+    email: &str,
+    new_email: &str,
+    token: &str,
+) -> Result<(), APIError> {
+    send_confirmation_email(settings, new_email, token)?;
+    match send_change_notice_email(settings, email, new_email) {
+        Ok(_) => {}
+        Err(e) => log::warn!("Unable to send email change notice: {:?}", e),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ConfirmForm {
+    token: String,
+}
+
+//Following the link proves access to the new address, so no authentication is needed
+pub async fn confirm(
+    form: ValidatedForm<ConfirmForm>,
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, APIError> {
+    let source = EventSource::from_request(&req, &state.settings.app.trusted_proxies);
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+
+        let (change, user): (EmailChange, User) = match EC::email_changes
+            .inner_join(U::users)
+            .filter(U::deleted_at.is_null())
+            .filter(EC::token_hash.eq(hash_token(&form.token)))
+            .first::<(EmailChange, User)>(&db)
+            .optional()?
+        {
+            Some(r) => r,
+            None => return Err(APIError::IncorrectCredentials),
+        };
+        if change.expires < Utc::now() {
+            return Err(APIError::BadRequest {
+                code: "TOKEN_EXPIRED".to_string(),
+                description: Some("The confirmation link has expired".to_string()),
+            });
+        }
+
+        let user: User = db.transaction::<_, APIError, _>(|| {
+            //The address may have been taken since the change was requested
+            assert_email_available(&db, &change.new_email)?;
+            let before = UserResponseItem::from(user.clone());
+            let user: User = diesel::update(&user)
+                .set(U::email.eq(&change.new_email))
+                .get_result(&db)?;
+            diesel::delete(&change).execute(&db)?;
+            let after = UserResponseItem::from(user.clone());
+            let actor_id = Some(user.id);
+            record_change_as(
+                &db,
+                actor_id,
+                AuditTarget::User,
+                user.id,
+                Some(&before),
+                Some(&after),
+            )?;
+            record_event(&db, user.id, SecurityEventKind::EmailChanged, true, &source)?;
+            Ok(user)
+        })?;
+
+        Ok(UserResponseItem::from(user))
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
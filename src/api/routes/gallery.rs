@@ -1,3 +1,5 @@
+use crate::api::audit::{record_change, AuditTarget};
+use crate::api::auth::{AuthenticatedUser, Role};
 use crate::api::errors::APIError;
 use crate::api::ok_json;
 use crate::ext::image_exif::read_image;
@@ -45,6 +47,24 @@ pub struct GalleryItemResponse {
     pub files: Vec<GalleryFileResponse>,
 }
 
+//The fields of a gallery item which are recorded in the audit log
+#[derive(Serialize)]
+struct GalleryItemAudit {
+    description: String,
+    category: String,
+    position: String,
+}
+
+impl From<&GalleryItem> for GalleryItemAudit {
+    fn from(i: &GalleryItem) -> Self {
+        GalleryItemAudit {
+            description: i.description.clone(),
+            category: i.category.clone(),
+            position: i.position.to_string(),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct GalleryFileResponse {
     url: Url,
@@ -150,9 +170,11 @@ static IMG_WIDTHS: [u32; 7] = [100, 300, 500, 750, 1000, 1500, 2500];
 const JPEG_QUALITY: u8 = 80;
 
 pub async fn create_item(
+    auth: AuthenticatedUser,
     state: Data<AppState>,
     form: ValidatedMultipartForm<CreateGalleryItem>,
 ) -> Result<HttpResponse, APIError> {
+    auth.require(Role::Editor)?;
     web::block(move || -> Result<_, APIError> {
         // Check uploaded file is valid image
         let form = form.into_inner();
@@ -188,6 +210,15 @@ pub async fn create_item(
                     GalleryItems::category.eq(form.category.serialize()),
                 ))
                 .get_result(&db)?;
+            let after = GalleryItemAudit::from(&gallery_item);
+            record_change(
+                &db,
+                &auth,
+                AuditTarget::GalleryItem,
+                gallery_item.id,
+                None,
+                Some(&after),
+            )?;
 
             let mut widths: Vec<u32> = IMG_WIDTHS
                 .to_vec()
@@ -243,9 +274,11 @@ pub async fn create_item(
 }
 
 pub async fn delete_item(
+    auth: AuthenticatedUser,
     state: Data<AppState>,
     item_id: Path<i32>,
 ) -> Result<HttpResponse, APIError> {
+    auth.require(Role::Editor)?;
     web::block(move || -> Result<_, APIError> {
         let db = state.new_connection();
         let (item, original_file): (GalleryItem, File) = GalleryItems::gallery_items
@@ -263,6 +296,15 @@ pub async fn delete_item(
         files.push(original_file);
 
         db.transaction::<_, APIError, _>(|| {
+            let before = GalleryItemAudit::from(&item);
+            record_change(
+                &db,
+                &auth,
+                AuditTarget::GalleryItem,
+                item.id,
+                Some(&before),
+                None,
+            )?;
             // Delete gallery file mappings
             diesel::delete(GalleryFiles::gallery_files.filter(GalleryFiles::item_id.eq(item.id)))
                 .execute(&db)?;
@@ -313,10 +355,12 @@ fn validate_update_gallery_item(x: &UpdateGalleryItem) -> Result<(), ValidationE
 }
 
 pub async fn update_item(
+    auth: AuthenticatedUser,
     state: Data<AppState>,
     item_id: Path<i32>,
     form: ValidatedForm<UpdateGalleryItem>,
 ) -> Result<HttpResponse, APIError> {
+    auth.require(Role::Editor)?;
     web::block(move || -> Result<_, APIError> {
         let db = state.new_connection();
         let target: GalleryItem = GalleryItems::gallery_items
@@ -371,17 +415,25 @@ pub async fn update_item(
             None
         };
 
-        let query = diesel::update(&target)
-            .set(&GalleryItemChange {
-                description: form.description.clone(),
-                position: new_pos,
-                category: form.category.serialize(),
-            })
-            .execute(&db)?;
-        if query < 1 {
-            return Err(APIError::NotFound);
-        }
-        Ok(())
+        db.transaction::<_, APIError, _>(|| {
+            let updated: GalleryItem = diesel::update(&target)
+                .set(&GalleryItemChange {
+                    description: form.description.clone(),
+                    position: new_pos,
+                    category: form.category.serialize(),
+                })
+                .get_result(&db)?;
+            let before = GalleryItemAudit::from(&target);
+            let after = GalleryItemAudit::from(&updated);
+            record_change(
+                &db,
+                &auth,
+                AuditTarget::GalleryItem,
+                target.id,
+                Some(&before),
+                Some(&after),
+            )
+        })
     })
     .map_ok(ok_json)
     .map_err(APIError::from)
@@ -0,0 +1,299 @@
+use crate::api::audit::{record_change_as, AuditTarget};
+use crate::api::auth::{AuthenticatedUser, Role};
+use crate::api::errors::APIError;
+use crate::api::ok_json;
+use crate::api::password::{check_password_policy, hash_password};
+use crate::api::routes::session::AUTH_TOKEN_BYTES;
+use crate::api::routes::users::{assert_email_available, UserResponseItem};
+use crate::api::token::{generate_token, hash_token};
+use crate::ext::postgres::functions::*;
+use crate::models::{Invitation, NewUser, User};
+use crate::schema::invitations::dsl as I;
+use crate::schema::users::dsl as U;
+use crate::settings::Settings;
+use crate::state::{AppState, Connection};
+use actix_validated_forms::form::ValidatedForm;
+use actix_web::web::{Data, Path};
+use actix_web::{web, HttpResponse};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use futures::TryFutureExt;
+use lettre::Transport;
+use lettre_email::EmailBuilder;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use validator::Validate;
+
+#[derive(Serialize)]
+struct InvitationResponseItem {
+    id: i32,
+    email: String,
+    role: Role,
+    invited_by: Option<i32>,
+    created: i64,
+    expires: i64,
+    expired: bool,
+}
+
+impl From<Invitation> for InvitationResponseItem {
+    fn from(i: Invitation) -> Self {
+        InvitationResponseItem {
+            id: i.id,
+            expired: i.expires < Utc::now(),
+            email: i.email,
+            role: i.role.parse().unwrap(),
+            invited_by: i.invited_by,
+            created: i.created.timestamp(),
+            expires: i.expires.timestamp(),
+        }
+    }
+}
+
+fn already_invited_error() -> APIError {
+    APIError::BadRequest {
+        code: "ALREADY_INVITED".to_owned(),
+        description: Some("An invitation has already been sent to this email".to_string()),
+    }
+}
+
+fn invitation_url(settings: &Settings) -> Result<Url, APIError> {
+    match &settings.invitations.url {
+        Some(u) => Ok(u.clone()),
+        None => Err(APIError::NotImplemented),
+    }
+}
+
+fn send_invitation_email(
+    settings: &Settings,
+    inviter: &str,
+    email: &str,
+    token: &str,
+) -> Result<(), APIError> {
+    let mut mailer = settings.mailer.smtp_transport()?;
+
+    let mut url = invitation_url(settings)?;
+    url.query_pairs_mut().append_pair("email", email);
+    url.query_pairs_mut().append_pair("token", token);
+    let body = format!(
+        "{} has invited you to manage the Kiwi Joinery website.\n\n\
+        To accept, choose your name and password using the link below:\n\n{}\n\n\
+        This link expires in {} hours.",
+        inviter, url, settings.invitations.expiry_hours
+    );
+
+    let email = EmailBuilder::new()
+        .to(email)
+        .from(settings.mailer.get_from_address())
+        .reply_to("noreply@kiwijoinerydevon.co.uk")
+        .subject("Kiwi Website Admin Invitation")
+        .body(body)
+        .build()
+        .unwrap();
+    mailer.send(email.into())?;
+    Ok(())
+}
+
+//Any previously sent link for the invitation stops working
+fn send_new_token(
+    db: &Connection,
+    settings: &Settings,
+    inviter: &str,
+    invitation: &Invitation,
+) -> Result<Invitation, APIError> {
+    let token = generate_token(AUTH_TOKEN_BYTES);
+    let expires = Utc::now() + Duration::hours(settings.invitations.expiry_hours);
+    let invitation: Invitation = diesel::update(invitation)
+        .set((I::token_hash.eq(hash_token(&token)), I::expires.eq(expires)))
+        .get_result(db)?;
+    send_invitation_email(settings, inviter, &invitation.email, &token)?;
+    Ok(invitation)
+}
+
+pub async fn list(
+    auth: AuthenticatedUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    auth.require(Role::Owner)?;
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let invitations: Vec<Invitation> = I::invitations
+            .order(I::created.desc())
+            .load::<Invitation>(&db)?;
+        Ok(invitations
+            .into_iter()
+            .map(InvitationResponseItem::from)
+            .collect::<Vec<_>>())
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateInvitationForm {
+    #[validate(email)]
+    email: String,
+    #[serde(default)]
+    role: Role,
+}
+
+pub async fn create(
+    auth: AuthenticatedUser,
+    form: ValidatedForm<CreateInvitationForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    auth.require(Role::Owner)?;
+    invitation_url(&state.settings)?;
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let email = form.email.to_ascii_lowercase();
+        assert_email_available(&db, &email)?;
+        let pending = I::invitations
+            .filter(lower(I::email).eq(&email))
+            .filter(I::expires.gt(Utc::now()))
+            .count()
+            .get_result::<i64>(&db)?;
+        if pending > 0 {
+            return Err(already_invited_error());
+        }
+
+        //The invitation isn't kept if the email can't be sent
+        let token = generate_token(AUTH_TOKEN_BYTES);
+        let expires = Utc::now() + Duration::hours(state.settings.invitations.expiry_hours);
+        db.transaction::<_, APIError, _>(|| {
+            //A lapsed invitation is replaced rather than having to be revoked first
+            diesel::delete(
+                I::invitations
+                    .filter(lower(I::email).eq(&email))
+                    .filter(I::expires.le(Utc::now())),
+            )
+            .execute(&db)?;
+            let invitation: Invitation = diesel::insert_into(I::invitations)
+                .values((
+                    I::email.eq(&email),
+                    I::role.eq(form.role.serialize()),
+                    I::invited_by.eq(auth.user_id()),
+                    I::token_hash.eq(hash_token(&token)),
+                    I::expires.eq(expires),
+                ))
+                .get_result(&db)?;
+            send_invitation_email(&state.settings, &auth.user.name, &email, &token)?;
+            Ok(InvitationResponseItem::from(invitation))
+        })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+//Sends a new link, which also extends the expiry
+pub async fn resend(
+    auth: AuthenticatedUser,
+    invitation_id: Path<i32>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    auth.require(Role::Owner)?;
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let invitation: Invitation = I::invitations
+            .find(invitation_id.into_inner())
+            .get_result(&db)?;
+        db.transaction::<_, APIError, _>(|| {
+            let invitation = send_new_token(&db, &state.settings, &auth.user.name, &invitation)?;
+            Ok(InvitationResponseItem::from(invitation))
+        })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+pub async fn revoke(
+    auth: AuthenticatedUser,
+    invitation_id: Path<i32>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    auth.require(Role::Owner)?;
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let deleted =
+            diesel::delete(I::invitations.find(invitation_id.into_inner())).execute(&db)?;
+        if deleted < 1 {
+            return Err(APIError::NotFound);
+        }
+        Ok(())
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct AcceptInvitationForm {
+    email: String,
+    token: String,
+    #[validate(length(min = 1, max = 255))]
+    name: String,
+    password: String,
+}
+
+//Creates the user for an invitation, with the name and password they have chosen
+pub async fn accept(
+    form: ValidatedForm<AcceptInvitationForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+
+        let invitation: Invitation = match I::invitations
+            .filter(lower(I::email).eq(&form.email.to_ascii_lowercase()))
+            .filter(I::token_hash.eq(hash_token(&form.token)))
+            .first::<Invitation>(&db)
+            .optional()?
+        {
+            Some(i) => i,
+            None => return Err(APIError::IncorrectCredentials),
+        };
+        if invitation.expires < Utc::now() {
+            return Err(APIError::BadRequest {
+                code: "TOKEN_EXPIRED".to_string(),
+                description: Some("The invitation has expired".to_string()),
+            });
+        }
+
+        let policy = &state.settings.password_policy;
+        check_password_policy(policy, &form.password, &form.name, &invitation.email)?;
+        let hashed = hash_password(&state.settings.password_hashing, &form.password)?;
+
+        let user: User = db.transaction::<_, APIError, _>(|| {
+            assert_email_available(&db, &invitation.email)?;
+            let user: User = diesel::insert_into(U::users)
+                .values(&NewUser {
+                    name: form.name.clone(),
+                    email: invitation.email.clone(),
+                    password_hash: Some(hashed),
+                    role: invitation.role.clone(),
+                })
+                .get_result(&db)?;
+            diesel::delete(&invitation).execute(&db)?;
+
+            //The user is recorded as being created by whoever invited them
+            let after = UserResponseItem::from(user.clone());
+            let actor_id = invitation.invited_by;
+            record_change_as(
+                &db,
+                actor_id,
+                AuditTarget::User,
+                user.id,
+                None,
+                Some(&after),
+            )?;
+            Ok(user)
+        })?;
+
+        Ok(UserResponseItem::from(user))
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
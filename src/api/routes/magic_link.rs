@@ -0,0 +1,176 @@
+use crate::api::actix::{ClientIpExt, HeaderMapExt};
+use crate::api::errors::APIError;
+use crate::api::ok_json;
+use crate::api::routes::session::{assert_not_locked, complete_first_factor, AUTH_TOKEN_BYTES};
+use crate::api::routes::tokens::{check_claim, check_unused};
+use crate::api::token::{generate_token, hash_token};
+use crate::ext::postgres::functions::*;
+use crate::models::{MagicLinkToken, User};
+use crate::schema::magic_link_tokens::dsl as M;
+use crate::schema::users::dsl as U;
+use crate::settings::Settings;
+use crate::state::{AppState, Connection};
+use actix_validated_forms::form::ValidatedForm;
+use actix_web::web::{Data, Form};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use futures::TryFutureExt;
+use ipnetwork::IpNetwork;
+use lettre::Transport;
+use lettre_email::EmailBuilder;
+use serde::Deserialize;
+use url::Url;
+use validator::Validate;
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct MagicLinkRequest {
+    #[validate(email)]
+    email: String,
+}
+
+fn send_magic_link_email(
+    settings: &Settings,
+    link_url: &Url,
+    email: &str,
+    token: &str,
+) -> Result<(), APIError> {
+    let mut mailer = settings.mailer.smtp_transport()?;
+
+    let mut url = link_url.clone();
+    url.query_pairs_mut().append_pair("email", email);
+    url.query_pairs_mut().append_pair("token", token);
+    let body = format!(
+        "Kiwi Admin Login Link: \n\n{}\n\nThis link expires in {} minutes and can only be used once.",
+        url, settings.magic_link.expiry_minutes
+    );
+
+    let email = EmailBuilder::new()
+        .to(email)
+        .from(settings.mailer.get_from_address())
+        .reply_to("noreply@kiwijoinerydevon.co.uk")
+        .subject("Kiwi Website Login Link")
+        .body(body)
+        .build()
+        .unwrap();
+    mailer.send(email.into())?;
+    Ok(())
+}
+
+//Issues a new login token for the user, any previously issued tokens are expired
+fn create_magic_token(
+    db: &Connection,
+    settings: &Settings,
+    user: &User,
+) -> Result<String, APIError> {
+    let token = generate_token(AUTH_TOKEN_BYTES);
+    let now = Utc::now();
+    diesel::update(
+        MagicLinkToken::belonging_to(user)
+            .filter(M::used.is_null())
+            .filter(M::expires.gt(now)),
+    )
+    .set(M::expires.eq(now))
+    .execute(db)?;
+    diesel::insert_into(M::magic_link_tokens)
+        .values((
+            M::user_id.eq(user.id),
+            M::token_hash.eq(hash_token(&token)),
+            M::expires.eq(now + Duration::minutes(settings.magic_link.expiry_minutes)),
+        ))
+        .execute(db)?;
+    Ok(token)
+}
+
+//Like password reset requests this always succeeds, with the work done in the background
+pub async fn request(
+    state: Data<AppState>,
+    form: ValidatedForm<MagicLinkRequest>,
+) -> Result<HttpResponse, APIError> {
+    let link_url = match &state.settings.magic_link.url {
+        Some(u) => u.clone(),
+        None => return Err(APIError::NotImplemented),
+    };
+    actix_rt::spawn(async move {
+        let result = web::block(move || -> Result<_, APIError> {
+            let db = state.new_connection();
+            let user: Option<User> = U::users
+                .filter(lower(U::email).eq(&form.email.to_ascii_lowercase()))
+                .filter(U::deleted_at.is_null())
+                .first::<User>(&db)
+                .optional()?;
+            if let Some(user) = user {
+                let token = create_magic_token(&db, &state.settings, &user)?;
+                send_magic_link_email(&state.settings, &link_url, &user.email, &token)?;
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(e) = result {
+            log::warn!("Unable to process magic link request: {:?}", e);
+        }
+    });
+    Ok(ok_json(()))
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkLoginForm {
+    email: String,
+    token: String,
+}
+
+const LOGIN_LINK: &str = "login link";
+
+//Exchanges a login link token for a session, in the same way as password_login
+pub async fn login(
+    form: Form<MagicLinkLoginForm>,
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, APIError> {
+    let ip_addr = req.client_ip(&state.settings.app.trusted_proxies)?;
+    let ip_net = IpNetwork::from(ip_addr);
+    let ua_opt = req.headers().user_agent();
+
+    web::block(move || {
+        let db = state.new_connection();
+
+        let user_agent = match ua_opt {
+            Some(t) => t,
+            None => return Err(APIError::BadAgent),
+        };
+
+        let (magic, user): (MagicLinkToken, User) = match M::magic_link_tokens
+            .inner_join(U::users)
+            .filter(lower(U::email).eq(&form.email.to_ascii_lowercase()))
+            .filter(U::deleted_at.is_null())
+            .filter(M::token_hash.eq(hash_token(&form.token)))
+            .first::<(MagicLinkToken, User)>(&db)
+            .optional()?
+        {
+            Some(r) => r,
+            None => return Err(APIError::IncorrectCredentials),
+        };
+        check_unused(magic.used.is_some(), magic.expires < Utc::now(), LOGIN_LINK)?;
+        assert_not_locked(&user)?;
+
+        let (response, notice) = db.transaction::<_, APIError, _>(|| {
+            let claimed = diesel::update(
+                M::magic_link_tokens
+                    .filter(M::id.eq(magic.id))
+                    .filter(M::used.is_null())
+                    .filter(M::expires.gt(diesel::dsl::now)),
+            )
+            .set(M::used.eq(diesel::dsl::now))
+            .execute(&db)?;
+            check_claim(claimed, magic.expires < Utc::now(), LOGIN_LINK)?;
+            complete_first_factor(&db, &state.settings, user, ip_net, user_agent)
+        })?;
+        if let Some(n) = notice {
+            n.send(&state.settings);
+        }
+        Ok(response)
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
@@ -1,5 +1,12 @@
+pub mod audit_log;
 pub mod contact;
+pub mod email_change;
 pub mod gallery;
+pub mod invitations;
+pub mod magic_link;
 pub mod password_reset;
 pub mod session;
+pub mod tokens;
+pub mod totp;
 pub mod users;
+pub mod webauthn;
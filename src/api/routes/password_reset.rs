@@ -1,15 +1,21 @@
 use crate::api::errors::APIError;
 use crate::api::ok_json;
+use crate::api::password::{check_password_policy, hash_password};
 use crate::api::routes::session::AUTH_TOKEN_BYTES;
-use crate::api::token::generate_token;
+use crate::api::routes::tokens::{check_claim, check_unused};
+use crate::api::security_events::{record_event, EventSource, SecurityEventKind};
+use crate::api::token::{generate_token, hash_token};
 use crate::ext::postgres::functions::*;
-use crate::models::User;
+use crate::models::{PasswordResetToken, User};
+use crate::schema::password_reset_tokens::dsl as P;
+use crate::schema::sessions::dsl as S;
 use crate::schema::users::dsl as U;
-use crate::state::AppState;
+use crate::settings::Settings;
+use crate::state::{AppState, Connection};
 use actix_validated_forms::form::ValidatedForm;
 use actix_web::web::Data;
-use actix_web::{web, HttpResponse};
-use bcrypt::{hash, DEFAULT_COST};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
 use diesel::prelude::*;
 use futures::TryFutureExt;
 use lettre::Transport;
@@ -23,11 +29,7 @@ pub struct ResetRequest {
     email: String,
 }
 
-pub fn send_reset_email(
-    settings: &crate::settings::Settings,
-    email: &str,
-    token: &str,
-) -> Result<(), APIError> {
+pub fn send_reset_email(settings: &Settings, email: &str, token: &str) -> Result<(), APIError> {
     let mut mailer = settings.mailer.smtp_transport()?;
 
     let mut url = settings.app.password_reset_url.clone();
@@ -47,77 +49,163 @@ pub fn send_reset_email(
     Ok(())
 }
 
+//Sent when a reset is requested for an address without an account
+pub fn send_unknown_account_email(settings: &Settings, email: &str) -> Result<(), APIError> {
+    let mut mailer = settings.mailer.smtp_transport()?;
+
+    let body = format!(
+        "Someone requested a Kiwi Admin password reset for {}, but no account exists \
+        with this email address.\n\nIf this wasn't you then you can ignore this email.",
+        email
+    );
+
+    let email = EmailBuilder::new()
+        .to(email)
+        .from(settings.mailer.get_from_address())
+        .reply_to("noreply@kiwijoinerydevon.co.uk")
+        .subject("Kiwi Website Password Reset")
+        .body(body)
+        .build()
+        .unwrap();
+    mailer.send(email.into())?;
+    Ok(())
+}
+
+//Issues a new reset token for the user, any previously issued tokens are expired
+pub fn create_reset_token(
+    db: &Connection,
+    settings: &Settings,
+    user: &User,
+) -> Result<String, APIError> {
+    let token = generate_token(AUTH_TOKEN_BYTES);
+    let now = Utc::now();
+    diesel::update(
+        PasswordResetToken::belonging_to(user)
+            .filter(P::used.is_null())
+            .filter(P::expires.gt(now)),
+    )
+    .set(P::expires.eq(now))
+    .execute(db)?;
+    diesel::insert_into(P::password_reset_tokens)
+        .values((
+            P::user_id.eq(user.id),
+            P::token_hash.eq(hash_token(&token)),
+            P::expires.eq(now + Duration::hours(settings.password_reset.expiry_hours)),
+        ))
+        .execute(db)?;
+    Ok(token)
+}
+
+//Always succeeds, the work is done in the background so that neither the response
+//nor its timing reveal whether an account exists for the email address
 pub async fn request(
     state: Data<AppState>,
     email: ValidatedForm<ResetRequest>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, APIError> {
-    web::block(move || -> Result<_, APIError> {
-        let db = state.new_connection();
+    let source = EventSource::from_request(&req, &state.settings.app.trusted_proxies);
+    actix_rt::spawn(async move {
+        let result = web::block(move || -> Result<_, APIError> {
+            let db = state.new_connection();
 
-        let user: User = match U::users
-            .filter(lower(U::email).eq(&email.email.to_ascii_lowercase()))
-            .first::<User>(&db)
-            .optional()?
-        {
-            Some(r) => r,
-            None => {
-                return Err(APIError::BadRequest {
-                    code: "INCORRECT_EMAIL".to_string(),
-                    description: Some("Email address not found".to_string()),
-                })
-            }
-        };
+            let user: Option<User> = U::users
+                .filter(lower(U::email).eq(&email.email.to_ascii_lowercase()))
+                .filter(U::deleted_at.is_null())
+                .first::<User>(&db)
+                .optional()?;
 
-        let token = match user.password_reset_token {
-            Some(t) => t,
-            None => {
-                let token = generate_token(AUTH_TOKEN_BYTES);
-                diesel::update(&user)
-                    .set(U::password_reset_token.eq(&token))
-                    .execute(&db)?;
-                token
+            match user {
+                Some(user) => {
+                    let kind = SecurityEventKind::PasswordResetRequested;
+                    record_event(&db, user.id, kind, true, &source)?;
+                    let token = create_reset_token(&db, &state.settings, &user)?;
+                    send_reset_email(&state.settings, &email.email, &token)?;
+                }
+                None => {
+                    if state.settings.password_reset.notify_unknown_email {
+                        send_unknown_account_email(&state.settings, &email.email)?;
+                    }
+                }
             }
-        };
-
-        send_reset_email(&state.settings, &email.email, &token)?;
-
-        Ok(())
-    })
-    .map_ok(ok_json)
-    .map_err(APIError::from)
-    .await
+            Ok(())
+        })
+        .await;
+        if let Err(e) = result {
+            log::warn!("Unable to process password reset request: {:?}", e);
+        }
+    });
+    Ok(ok_json(()))
 }
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct ResetSubmit {
     token: String,
     email: String,
-    #[validate(length(min = 16, max = 255))]
     new_password: String,
 }
 
+const RESET_LINK: &str = "reset link";
+
 pub async fn submit(
     state: Data<AppState>,
     form: ValidatedForm<ResetSubmit>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, APIError> {
+    let source = EventSource::from_request(&req, &state.settings.app.trusted_proxies);
     web::block(move || -> Result<_, APIError> {
         let db = state.new_connection();
 
-        let mut user: User = match U::users
+        let (reset, user): (PasswordResetToken, User) = match P::password_reset_tokens
+            .inner_join(U::users)
             .filter(lower(U::email).eq(&form.email.to_ascii_lowercase()))
-            .filter(U::password_reset_token.eq(&form.token))
-            .first::<User>(&db)
+            .filter(U::deleted_at.is_null())
+            .filter(P::token_hash.eq(hash_token(&form.token)))
+            .first::<(PasswordResetToken, User)>(&db)
             .optional()?
         {
             Some(r) => r,
             None => return Err(APIError::IncorrectCredentials),
         };
+        let kind = SecurityEventKind::PasswordReset;
+        let unused = check_unused(reset.used.is_some(), reset.expires < Utc::now(), RESET_LINK);
+        if let Err(e) = unused {
+            record_event(&db, user.id, kind, false, &source)?;
+            return Err(e);
+        }
 
-        let new = hash(&form.new_password, DEFAULT_COST)?;
-        user.password_hash = Some(new);
-        user.password_reset_token = None;
+        let policy = &state.settings.password_policy;
+        check_password_policy(policy, &form.new_password, &user.name, &user.email)?;
+        let new = hash_password(&state.settings.password_hashing, &form.new_password)?;
 
-        diesel::update(&user).set(&user).execute(&db)?;
+        let claimed = db.transaction::<_, APIError, _>(|| {
+            let claimed = diesel::update(
+                P::password_reset_tokens
+                    .filter(P::id.eq(reset.id))
+                    .filter(P::used.is_null())
+                    .filter(P::expires.gt(diesel::dsl::now)),
+            )
+            .set(P::used.eq(diesel::dsl::now))
+            .execute(&db)?;
+            if claimed != 1 {
+                return Ok(claimed);
+            }
+            //Proving access to the email address unlocks the account
+            diesel::update(&user)
+                .set((
+                    U::password_hash.eq(new),
+                    U::failed_login_attempts.eq(0),
+                    U::locked_until.eq(None::<DateTime<Utc>>),
+                ))
+                .execute(&db)?;
+            //Anyone who may have had access to the account is logged out
+            diesel::delete(S::sessions.filter(S::user_id.eq(user.id))).execute(&db)?;
+            record_event(&db, user.id, kind, true, &source)?;
+            Ok(claimed)
+        })?;
+        if let Err(e) = check_claim(claimed, reset.expires < Utc::now(), RESET_LINK) {
+            record_event(&db, user.id, kind, false, &source)?;
+            return Err(e);
+        }
         Ok(())
     })
     .map_ok(ok_json)
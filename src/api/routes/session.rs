@@ -1,21 +1,34 @@
-use crate::api::actix::{ConnectionInfoExt, HeaderMapExt};
+use crate::api::actix::{ClientIpExt, HeaderMapExt};
 use crate::api::auth::AuthenticatedUser;
 use crate::api::errors::APIError;
 use crate::api::ok_json;
+use crate::api::password::{hash_password, needs_rehash, verify_password};
+use crate::api::routes::tokens::check_claim;
 use crate::api::routes::users::UserResponseItem;
-use crate::api::token::generate_token;
+use crate::api::security_events::{record_event, EventSource, SecurityEventKind};
+use crate::api::token::{generate_token, hash_token};
+use crate::api::totp;
 use crate::ext::postgres::functions::*;
-use crate::models::{NewSession, Session, User};
+use crate::models::{LoginChallenge, NewSession, Session, User};
+use crate::schema::login_challenges::dsl as LC;
 use crate::schema::sessions::dsl as S;
 use crate::schema::users::dsl as U;
-use crate::state::AppState;
+use crate::settings::Settings;
+use crate::state::{AppState, Connection};
+use actix_validated_forms::form::ValidatedForm;
 use actix_web::web::{Data, Form, Path};
 use actix_web::{web, HttpRequest, HttpResponse};
-use bcrypt::verify;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use futures::TryFutureExt;
+use ipnetwork::IpNetwork;
+use lettre::Transport;
+use lettre_email::{Email, EmailBuilder};
 use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
+use url::Url;
+use validator::Validate;
+use woothee::parser::Parser;
+use woothee::woothee::VALUE_UNKNOWN;
 
 pub const AUTH_TOKEN_BYTES: u8 = 32;
 
@@ -26,18 +39,219 @@ pub struct LoginForm {
 }
 
 #[derive(Serialize)]
-struct LoginResponse {
+pub struct LoginResponse {
     token: String,
     user: UserResponseItem,
 }
 
+//Returned instead of a session when a second factor is required
+#[derive(Serialize)]
+pub struct ChallengeResponse {
+    totp_required: bool,
+    challenge: String,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum FirstFactorResponse {
+    Session(LoginResponse),
+    Challenge(ChallengeResponse),
+}
+
+//Too many failed attempts temporarily lock the account, regardless of IP address
+pub fn assert_not_locked(user: &User) -> Result<(), APIError> {
+    match user.locked_until {
+        Some(t) if t > Utc::now() => Err(APIError::AccountLocked),
+        _ => Ok(()),
+    }
+}
+
+//Counts towards the lockout, incremented in the database so parallel attempts are all counted
+pub fn count_failed_attempt(db: &Connection, settings: &Settings, user: &User) -> QueryResult<()> {
+    let attempts: i32 = diesel::update(user)
+        .set(U::failed_login_attempts.eq(U::failed_login_attempts + 1))
+        .returning(U::failed_login_attempts)
+        .get_result(db)?;
+    if let Some(d) = settings.lockout.lockout_duration(attempts) {
+        diesel::update(user)
+            .set(U::locked_until.eq(Utc::now() + d))
+            .execute(db)?;
+    }
+    Ok(())
+}
+
+pub fn record_failed_login(
+    db: &Connection,
+    settings: &Settings,
+    user: &User,
+    source: &EventSource,
+) -> QueryResult<()> {
+    count_failed_attempt(db, settings, user)?;
+    record_event(db, user.id, SecurityEventKind::Login, false, source)?;
+    Ok(())
+}
+
+pub fn clear_failed_logins(db: &Connection, user: &User) -> QueryResult<()> {
+    diesel::update(user)
+        .set((
+            U::failed_login_attempts.eq(0),
+            U::locked_until.eq(None::<DateTime<Utc>>),
+        ))
+        .execute(db)?;
+    Ok(())
+}
+
+//Once the first factor has been verified, either issue a session or a challenge for the second
+pub fn complete_first_factor(
+    db: &Connection,
+    settings: &Settings,
+    user: User,
+    ip_net: IpNetwork,
+    user_agent: String,
+) -> Result<(FirstFactorResponse, Option<NewLoginNotice>), APIError> {
+    //With two factor enabled the session is only issued by totp_login
+    if user.totp_enabled {
+        let challenge = generate_token(AUTH_TOKEN_BYTES);
+        diesel::insert_into(LC::login_challenges)
+            .values((
+                LC::user_id.eq(user.id),
+                LC::token_hash.eq(hash_token(&challenge)),
+            ))
+            .execute(db)?;
+        let response = FirstFactorResponse::Challenge(ChallengeResponse {
+            totp_required: true,
+            challenge,
+        });
+        return Ok((response, None));
+    }
+
+    clear_failed_logins(db, &user)?;
+    let (response, notice) = start_session(db, settings, user, ip_net, user_agent)?;
+    Ok((FirstFactorResponse::Session(response), notice))
+}
+
+//An email about a login from a new device, which is held back until the session has been
+//committed
+pub struct NewLoginNotice {
+    email: Email,
+}
+
+impl NewLoginNotice {
+    //Sent from a separate thread, so that slow mail doesn't hold up the login, and the response
+    //time doesn't reveal whether the device was new
+    pub fn send(self, settings: &Settings) {
+        let email = self.email;
+        let mailer = settings.mailer.clone();
+        std::thread::spawn(move || {
+            let result = mailer
+                .smtp_transport()
+                .map_err(APIError::from)
+                .and_then(|mut t| t.send(email.into()).map_err(APIError::from));
+            if let Err(e) = result {
+                log::warn!("Unable to send new login email: {:?}", e);
+            }
+        });
+    }
+}
+
+fn new_login_notice(
+    settings: &Settings,
+    revoke_url: &Url,
+    user: &User,
+    session: &Session,
+    revoke_token: &str,
+) -> NewLoginNotice {
+    let mut url = revoke_url.clone();
+    url.query_pairs_mut().append_pair("token", revoke_token);
+    let (browser, os) = parse_device(&session.user_agent);
+    let body = format!(
+        "There was a new login to your Kiwi Admin account:\n\n\
+        Time: {}\nIP Address: {}\nDevice: {}\n\n\
+        If this wasn't you then log the device out using the link below, \
+        and change your password:\n\n{}",
+        session.created.format("%Y-%m-%d %H:%M:%S UTC"),
+        session.last_ip.ip(),
+        device_label(&browser, &os),
+        url
+    );
+
+    let email = EmailBuilder::new()
+        .to(user.email.as_str())
+        .from(settings.mailer.get_from_address())
+        .reply_to("noreply@kiwijoinerydevon.co.uk")
+        .subject("Kiwi Website New Login")
+        .body(body)
+        .build()
+        .unwrap();
+    NewLoginNotice { email }
+}
+
+//Creates a new session for the user, existing sessions are left alone as they may belong to
+//another client on the same IP + Agent
+pub fn start_session(
+    db: &Connection,
+    settings: &Settings,
+    user: User,
+    ip_net: IpNetwork,
+    user_agent: String,
+) -> Result<(LoginResponse, Option<NewLoginNotice>), APIError> {
+    //A live session for this IP + Agent means the device is already known
+    let expiry = &settings.sessions;
+    let known_device: bool = diesel::select(diesel::dsl::exists(
+        Session::belonging_to(&user)
+            .filter(S::last_ip.eq(&ip_net))
+            .filter(S::user_agent.eq(&user_agent))
+            .filter(S::last_used.ge(expiry.idle_cutoff()))
+            .filter(S::created.ge(expiry.lifetime_cutoff())),
+    ))
+    .get_result(db)?;
+
+    let source = EventSource {
+        ip: Some(ip_net),
+        user_agent: Some(user_agent.clone()),
+    };
+    record_event(db, user.id, SecurityEventKind::Login, true, &source)?;
+    diesel::update(&user)
+        .set(U::last_login.eq(diesel::dsl::now))
+        .execute(db)?;
+
+    //Let the user know about logins from a new device
+    let revoke_url = settings
+        .sessions
+        .revoke_url
+        .as_ref()
+        .filter(|_| user.notify_new_login && !known_device);
+    let revoke_token = revoke_url.map(|_| generate_token(AUTH_TOKEN_BYTES));
+    let token = generate_token(AUTH_TOKEN_BYTES);
+    let session = NewSession {
+        user_id: user.id,
+        token_hash: hash_token(&token),
+        last_ip: ip_net,
+        user_agent,
+        revoke_token_hash: revoke_token.as_deref().map(hash_token),
+    };
+    let session: Session = diesel::insert_into(S::sessions)
+        .values(&session)
+        .get_result(db)?;
+    let notice = match (revoke_url, &revoke_token) {
+        (Some(url), Some(revoke)) => Some(new_login_notice(settings, url, &user, &session, revoke)),
+        _ => None,
+    };
+
+    let response = LoginResponse {
+        token,
+        user: user.into(),
+    };
+    Ok((response, notice))
+}
+
 pub async fn password_login(
     form: Form<LoginForm>,
     state: Data<AppState>,
     req: HttpRequest,
 ) -> Result<HttpResponse, APIError> {
-    let ip_addr = req.connection_info().ip_address()?;
-    let ip_bin = bincode::serialize(&ip_addr).unwrap();
+    let ip_addr = req.client_ip(&state.settings.app.trusted_proxies)?;
+    let ip_net = IpNetwork::from(ip_addr);
     let ua_opt = req.headers().user_agent();
 
     web::block(move || {
@@ -51,6 +265,7 @@ pub async fn password_login(
         //Fetch the user with the submitted email
         let user: User = match U::users
             .filter(lower(U::email).eq(&form.email.to_ascii_lowercase()))
+            .filter(U::deleted_at.is_null())
             .first::<User>(&db)
             .optional()?
         {
@@ -58,6 +273,8 @@ pub async fn password_login(
             None => return Err(APIError::IncorrectCredentials),
         };
 
+        assert_not_locked(&user)?;
+
         //Check that the user does actually have a password set
         let hashed = match &user.password_hash {
             Some(val) => val.clone(),
@@ -65,39 +282,114 @@ pub async fn password_login(
         };
 
         //Check that the password matches
-        if !(verify(&form.password, &hashed)?) {
+        if !(verify_password(&hashed, &form.password)?) {
+            let source = EventSource {
+                ip: Some(ip_net),
+                user_agent: Some(user_agent),
+            };
+            record_failed_login(&db, &state.settings, &user, &source)?;
             return Err(APIError::IncorrectCredentials);
         };
 
-        //See if a session exists for this IP + Agent
-        let session: Option<Session> = Session::belonging_to(&user)
-            .filter(S::last_ip.eq(&ip_bin))
-            .filter(S::user_agent.eq(&user_agent))
-            .first::<Session>(&db)
-            .optional()?;
+        //Upgrade hashes using an old algorithm or parameters now the password is known
+        let params = &state.settings.password_hashing;
+        if needs_rehash(params, &hashed) {
+            diesel::update(&user)
+                .set(U::password_hash.eq(hash_password(params, &form.password)?))
+                .execute(&db)?;
+        }
+
+        let (response, notice) =
+            complete_first_factor(&db, &state.settings, user, ip_net, user_agent)?;
+        if let Some(n) = notice {
+            n.send(&state.settings);
+        }
+        Ok(response)
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct TotpLoginForm {
+    challenge: String,
+    code: String,
+}
+
+const LOGIN_CHALLENGE: &str = "login challenge";
+
+//Second step of login, accepts either a TOTP code or a recovery code
+pub async fn totp_login(
+    form: Form<TotpLoginForm>,
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, APIError> {
+    let ip_addr = req.client_ip(&state.settings.app.trusted_proxies)?;
+    let ip_net = IpNetwork::from(ip_addr);
+    let ua_opt = req.headers().user_agent();
+
+    web::block(move || {
+        let db = state.new_connection();
+
+        let user_agent = match ua_opt {
+            Some(t) => t,
+            None => return Err(APIError::BadAgent),
+        };
 
-        let token = match session {
-            Some(val) => val.token,
+        let cutoff = state.settings.sessions.login_challenge_cutoff();
+        let (challenge, user): (LoginChallenge, User) = match LC::login_challenges
+            .filter(LC::token_hash.eq(hash_token(&form.challenge)))
+            .filter(LC::created.ge(cutoff))
+            .inner_join(U::users)
+            .filter(U::deleted_at.is_null())
+            .first::<(LoginChallenge, User)>(&db)
+            .optional()?
+        {
+            Some(r) => r,
+            None => return Err(APIError::IncorrectCredentials),
+        };
+
+        assert_not_locked(&user)?;
+        //A wrong code leaves the challenge in place, so it can be tried again
+        let response = db.transaction::<_, APIError, _>(|| {
+            if !totp::verify_user_code(&db, &user, &form.code)? {
+                return Ok(None);
+            }
+            let claimed = diesel::delete(
+                LC::login_challenges
+                    .filter(LC::id.eq(challenge.id))
+                    .filter(LC::created.ge(cutoff)),
+            )
+            .execute(&db)?;
+            let expired = challenge.created < state.settings.sessions.login_challenge_cutoff();
+            check_claim(claimed, expired, LOGIN_CHALLENGE)?;
+            clear_failed_logins(&db, &user)?;
+            let session = start_session(
+                &db,
+                &state.settings,
+                user.clone(),
+                ip_net,
+                user_agent.clone(),
+            )?;
+            Ok(Some(session))
+        })?;
+        match response {
+            Some((response, notice)) => {
+                if let Some(n) = notice {
+                    n.send(&state.settings);
+                }
+                Ok(response)
+            }
             None => {
-                //If not create new token and session
-                let new_token = generate_token(AUTH_TOKEN_BYTES);
-                let session = NewSession {
-                    user_id: user.id,
-                    token: new_token.clone(),
-                    last_ip: ip_bin,
-                    user_agent,
+                let source = EventSource {
+                    ip: Some(ip_net),
+                    user_agent: Some(user_agent),
                 };
-                diesel::insert_into(S::sessions)
-                    .values(&session)
-                    .execute(&db)?;
-                new_token
+                record_failed_login(&db, &state.settings, &user, &source)?;
+                Err(APIError::IncorrectCredentials)
             }
-        };
-
-        Ok(LoginResponse {
-            token,
-            user: user.into(),
-        })
+        }
     })
     .map_ok(ok_json)
     .err_into()
@@ -112,6 +404,14 @@ pub async fn logout(
     web::block(move || -> Result<_, APIError> {
         let db = state.new_connection();
         diesel::delete(&auth.session).execute(&db)?;
+        let source = EventSource::from(&auth.session);
+        record_event(
+            &db,
+            auth.user_id(),
+            SecurityEventKind::Logout,
+            true,
+            &source,
+        )?;
         Ok(())
     })
     .map_ok(ok_json)
@@ -127,12 +427,57 @@ struct SessionResponseItem {
     last_ip: Option<String>,
     user_agent: String,
     is_current: bool,
+    name: Option<String>,
+    browser: Option<String>,
+    os: Option<String>,
+    label: String,
+}
+
+//The browser and OS names from a user agent, where they could be recognised
+fn parse_device(user_agent: &str) -> (Option<String>, Option<String>) {
+    let parsed = Parser::new().parse(user_agent);
+    let known = |v: &str| {
+        if v.is_empty() || v == VALUE_UNKNOWN {
+            None
+        } else {
+            Some(v.to_owned())
+        }
+    };
+    let browser = parsed.as_ref().and_then(|p| known(p.name));
+    let os = parsed.as_ref().and_then(|p| known(p.os));
+    (browser, os)
+}
+
+fn device_label(browser: &Option<String>, os: &Option<String>) -> String {
+    match (browser, os) {
+        (Some(b), Some(o)) => format!("{} on {}", b, o),
+        (Some(b), None) => b.clone(),
+        (None, Some(o)) => o.clone(),
+        (None, None) => "Unknown device".to_owned(),
+    }
 }
 
-fn ip_bytes_to_str(ip_bytes: Vec<u8>) -> Option<String> {
-    bincode::deserialize::<IpAddr>(&ip_bytes)
-        .ok()
-        .map(|ip| ip.to_string())
+impl SessionResponseItem {
+    fn new(s: Session, current: &Session) -> Self {
+        let (browser, os) = parse_device(&s.user_agent);
+        //Sessions are labelled by their name if set, otherwise the device
+        let label = match &s.name {
+            Some(n) => n.clone(),
+            None => device_label(&browser, &os),
+        };
+        SessionResponseItem {
+            id: s.id,
+            created: s.created.timestamp(),
+            last_used: s.last_used.timestamp(),
+            last_ip: Some(s.last_ip.ip().to_string()),
+            is_current: (s.id == current.id),
+            user_agent: s.user_agent,
+            name: s.name,
+            browser,
+            os,
+            label,
+        }
+    }
 }
 
 pub async fn list(
@@ -144,14 +489,7 @@ pub async fn list(
         let sessions: Vec<Session> = Session::belonging_to(&auth.user).load::<Session>(&db)?;
         let formatted = sessions
             .into_iter()
-            .map(|s| SessionResponseItem {
-                id: s.id,
-                created: s.created.timestamp(),
-                last_used: s.last_used.timestamp(),
-                last_ip: ip_bytes_to_str(s.last_ip),
-                user_agent: s.user_agent,
-                is_current: (s.id == auth.session.id),
-            })
+            .map(|s| SessionResponseItem::new(s, &auth.session))
             .collect::<Vec<_>>();
         Ok(formatted)
     })
@@ -173,6 +511,110 @@ pub async fn delete(
             .first::<Session>(&db)?;
 
         diesel::delete(&session).execute(&db)?;
+        let source = EventSource::from(&auth.session);
+        record_event(
+            &db,
+            auth.user_id(),
+            SecurityEventKind::SessionRevoked,
+            true,
+            &source,
+        )?;
+        Ok(())
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct UpdateSessionForm {
+    #[validate(length(max = 255))]
+    name: Option<String>,
+}
+
+//Allows the user to give one of their sessions a recognisable name
+pub async fn update(
+    auth: AuthenticatedUser,
+    session_id: Path<i32>,
+    form: ValidatedForm<UpdateSessionForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+
+        let session: Session = Session::belonging_to(&auth.user)
+            .filter(S::id.eq(session_id.into_inner()))
+            .first::<Session>(&db)?;
+
+        let name = form.name.as_ref().filter(|n| !n.trim().is_empty());
+        let session: Session = diesel::update(&session)
+            .set(S::name.eq(name))
+            .get_result(&db)?;
+        Ok(SessionResponseItem::new(session, &auth.session))
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+//Deletes all of the user's sessions except the currently authenticated one
+pub async fn delete_others(
+    auth: AuthenticatedUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        diesel::delete(
+            S::sessions
+                .filter(S::user_id.eq(auth.user_id()))
+                .filter(S::id.ne(auth.session.id)),
+        )
+        .execute(&db)?;
+        let source = EventSource::from(&auth.session);
+        record_event(
+            &db,
+            auth.user_id(),
+            SecurityEventKind::SessionRevoked,
+            true,
+            &source,
+        )?;
+        Ok(())
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct RevokeForm {
+    token: String,
+}
+
+//Logs out a session using the link from its new login email, so no authentication is needed
+pub async fn revoke(
+    form: Form<RevokeForm>,
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, APIError> {
+    let source = EventSource::from_request(&req, &state.settings.app.trusted_proxies);
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let session: Session = match S::sessions
+            .filter(S::revoke_token_hash.eq(hash_token(&form.token)))
+            .first::<Session>(&db)
+            .optional()?
+        {
+            Some(s) => s,
+            None => return Err(APIError::IncorrectCredentials),
+        };
+        diesel::delete(&session).execute(&db)?;
+        record_event(
+            &db,
+            session.user_id,
+            SecurityEventKind::SessionRevoked,
+            true,
+            &source,
+        )?;
         Ok(())
     })
     .map_ok(ok_json)
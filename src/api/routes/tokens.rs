@@ -0,0 +1,40 @@
+use crate::api::errors::APIError;
+
+// Single use tokens are claimed by a conditional update or delete that only matches a row
+// which is still unused and unexpired, so when several requests are made at the same time
+// with the same token only one of them can succeed.
+
+fn token_used_error(what: &str) -> APIError {
+    APIError::BadRequest {
+        code: "TOKEN_USED".to_string(),
+        description: Some(format!("The {} has already been used", what)),
+    }
+}
+
+fn token_expired_error(what: &str) -> APIError {
+    APIError::BadRequest {
+        code: "TOKEN_EXPIRED".to_string(),
+        description: Some(format!("The {} has expired", what)),
+    }
+}
+
+//For a token that has been loaded, before any work is done to claim it
+pub fn check_unused(used: bool, expired: bool, what: &str) -> Result<(), APIError> {
+    if used {
+        Err(token_used_error(what))
+    } else if expired {
+        Err(token_expired_error(what))
+    } else {
+        Ok(())
+    }
+}
+
+//The claim must have affected exactly one row, otherwise another request got there first
+//or the token expired in the meantime
+pub fn check_claim(claimed: usize, expired: bool, what: &str) -> Result<(), APIError> {
+    match claimed {
+        1 => Ok(()),
+        _ if expired => Err(token_expired_error(what)),
+        _ => Err(token_used_error(what)),
+    }
+}
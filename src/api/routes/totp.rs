@@ -0,0 +1,246 @@
+use crate::api::auth::{AuthenticatedUser, Role};
+use crate::api::errors::APIError;
+use crate::api::ok_json;
+use crate::api::password::verify_password;
+use crate::api::routes::session::{assert_not_locked, record_failed_login};
+use crate::api::security_events::EventSource;
+use crate::api::totp;
+use crate::models::User;
+use crate::schema::recovery_codes::dsl as RC;
+use crate::schema::users::dsl as U;
+use crate::settings::Settings;
+use crate::state::{AppState, Connection};
+use actix_validated_forms::form::ValidatedForm;
+use actix_web::web::{Data, Path};
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use futures::TryFutureExt;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use validator::Validate;
+
+//Two factor can only be enrolled by the user themselves
+fn assert_self(auth: &AuthenticatedUser, user_id: i32) -> Result<(), APIError> {
+    if user_id == auth.user_id() {
+        Ok(())
+    } else {
+        Err(APIError::Forbidden)
+    }
+}
+
+fn totp_enabled_error() -> APIError {
+    APIError::BadRequest {
+        code: "TOTP_ENABLED".to_owned(),
+        description: Some("Two factor authentication is already enabled".to_string()),
+    }
+}
+
+fn totp_not_enabled_error() -> APIError {
+    APIError::BadRequest {
+        code: "TOTP_NOT_ENABLED".to_owned(),
+        description: Some("Two factor authentication is not enabled".to_string()),
+    }
+}
+
+fn incorrect_code_error() -> APIError {
+    APIError::BadRequest {
+        code: "INCORRECT_CODE".to_owned(),
+        description: Some("The code was not valid".to_string()),
+    }
+}
+
+#[derive(Serialize)]
+struct EnrolResponse {
+    secret: String,
+    provisioning_uri: Url,
+}
+
+//Generates a new secret, which isn't used for login until it has been confirmed
+pub async fn enrol(
+    auth: AuthenticatedUser,
+    user_id: Path<i32>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    assert_self(&auth, user_id.into_inner())?;
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        if auth.user.totp_enabled {
+            return Err(totp_enabled_error());
+        }
+        let secret = totp::generate_secret();
+        diesel::update(&auth.user)
+            .set((
+                U::totp_secret.eq(&secret),
+                U::totp_last_step.eq(None::<i64>),
+            ))
+            .execute(&db)?;
+        Ok(EnrolResponse {
+            provisioning_uri: totp::provisioning_uri(&secret, &auth.user.email),
+            secret,
+        })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CodeForm {
+    #[validate(length(min = 1, max = 32))]
+    code: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+//Enables two factor once the user has proven their authenticator app works
+pub async fn confirm(
+    auth: AuthenticatedUser,
+    user_id: Path<i32>,
+    form: ValidatedForm<CodeForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    assert_self(&auth, user_id.into_inner())?;
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        if auth.user.totp_enabled {
+            return Err(totp_enabled_error());
+        }
+        let secret = match &auth.user.totp_secret {
+            Some(s) => s,
+            None => return Err(totp_not_enabled_error()),
+        };
+        if !totp::verify_code(&db, &auth.user, secret, &form.code)? {
+            return Err(incorrect_code_error());
+        }
+        let codes = totp::generate_recovery_codes();
+        db.transaction::<_, APIError, _>(|| {
+            diesel::update(&auth.user)
+                .set(U::totp_enabled.eq(true))
+                .execute(&db)?;
+            totp::store_recovery_codes(&db, &auth.user, &codes)?;
+            Ok(())
+        })?;
+        Ok(RecoveryCodesResponse {
+            recovery_codes: codes,
+        })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+//Replaces all existing recovery codes
+pub async fn regenerate_recovery_codes(
+    auth: AuthenticatedUser,
+    user_id: Path<i32>,
+    form: ValidatedForm<CodeForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    assert_self(&auth, user_id.into_inner())?;
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let secret = match (&auth.user.totp_secret, auth.user.totp_enabled) {
+            (Some(s), true) => s,
+            _ => return Err(totp_not_enabled_error()),
+        };
+        if !totp::verify_code(&db, &auth.user, secret, &form.code)? {
+            return Err(incorrect_code_error());
+        }
+        let codes = totp::generate_recovery_codes();
+        totp::store_recovery_codes(&db, &auth.user, &codes)?;
+        Ok(RecoveryCodesResponse {
+            recovery_codes: codes,
+        })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct DisableForm {
+    #[validate(length(min = 1, max = 32))]
+    code: Option<String>,
+    password: Option<String>,
+}
+
+//Users disabling their own two factor must prove it's them with a code or their password,
+//so that a stolen session isn't enough to remove it
+fn verify_disable(
+    db: &Connection,
+    settings: &Settings,
+    user: &User,
+    form: Option<&DisableForm>,
+    source: &EventSource,
+) -> Result<(), APIError> {
+    //An unconfirmed enrolment isn't protecting anything yet
+    if !user.totp_enabled {
+        return Ok(());
+    }
+    assert_not_locked(user)?;
+    let verified = match form {
+        Some(DisableForm {
+            code: Some(code), ..
+        }) => totp::verify_user_code(db, user, code)?,
+        Some(DisableForm {
+            password: Some(password),
+            ..
+        }) => match &user.password_hash {
+            Some(hashed) => verify_password(hashed, password)?,
+            None => false,
+        },
+        _ => {
+            return Err(APIError::BadRequest {
+                code: "VERIFICATION_REQUIRED".to_owned(),
+                description: Some("A code or the current password is required".to_string()),
+            })
+        }
+    };
+    if !verified {
+        record_failed_login(db, settings, user, source)?;
+        return Err(incorrect_code_error());
+    }
+    Ok(())
+}
+
+//Owners may also disable two factor for another user who has lost their device
+pub async fn disable(
+    auth: AuthenticatedUser,
+    user_id: Path<i32>,
+    form: Option<ValidatedForm<DisableForm>>,
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, APIError> {
+    let user_id = user_id.into_inner();
+    if user_id != auth.user_id() {
+        auth.require(Role::Owner)?;
+    }
+    let source = EventSource::from_request(&req, &state.settings.app.trusted_proxies);
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        if user_id == auth.user_id() {
+            let form = form.as_ref().map(|f| &**f);
+            verify_disable(&db, &state.settings, &auth.user, form, &source)?;
+        }
+        db.transaction::<_, APIError, _>(|| {
+            let updated = diesel::update(U::users.find(user_id).filter(U::deleted_at.is_null()))
+                .set((
+                    U::totp_secret.eq(None::<String>),
+                    U::totp_enabled.eq(false),
+                    U::totp_last_step.eq(None::<i64>),
+                ))
+                .execute(&db)?;
+            if updated < 1 {
+                return Err(APIError::NotFound);
+            }
+            diesel::delete(RC::recovery_codes.filter(RC::user_id.eq(user_id))).execute(&db)?;
+            Ok(())
+        })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
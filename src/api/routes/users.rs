@@ -1,32 +1,67 @@
-use crate::api::auth::AuthenticatedUser;
+use crate::api::audit::{record_change, record_restore, AuditTarget};
+use crate::api::auth::{AuthenticatedUser, Role};
 use crate::api::errors::APIError;
 use crate::api::ok_json;
-use crate::api::routes::password_reset::send_reset_email;
-use crate::api::routes::session::AUTH_TOKEN_BYTES;
-use crate::api::token::generate_token;
-use crate::ext::postgres::functions::strpos;
+use crate::api::password::{check_password_policy, hash_password, verify_password};
+use crate::api::routes::email_change::{request_email_change, send_email_change};
+use crate::api::routes::session::{assert_not_locked, count_failed_attempt};
+use crate::api::security_events::{record_event, EventSource, SecurityEventKind};
+use crate::ext::postgres::cursor::{
+    keyset_limit, keyset_order, Cursor, CursorResult, PageResult, TimestampKey,
+};
 use crate::ext::postgres::functions::*;
 use crate::ext::postgres::limit::{CountedLimitResult, CountingLimit};
-use crate::models::{NewUser, User};
+use crate::models::{SecurityEvent, User};
+use crate::schema::security_events::dsl as SE;
 use crate::schema::users::dsl as U;
+use crate::schema::users::BoxedQuery;
 use crate::state::AppState;
 use crate::state::Connection;
 use actix_validated_forms::form::ValidatedForm;
 use actix_validated_forms::query::ValidatedQuery;
 use actix_web::web::{Data, Path};
 use actix_web::{web, HttpResponse};
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::pg::Pg;
 use diesel::prelude::*;
 use futures::TryFutureExt;
 use serde::{Deserialize, Serialize};
 use validator::Validate;
 
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum UserSort {
+    Name,
+    Email,
+    Created,
+    LastLogin,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+//There is no pending invite filter, invited users don't exist until they accept, so pending
+//invitations are listed by invitations::list instead
 #[derive(Debug, Deserialize, Validate)]
 #[serde(default)]
 pub struct ListUserQuery {
     #[validate(range(min = 1, max = 100))]
     limit: i64,
     offset: i64,
+    //Cursor pagination is used when asked for, or when continuing from a cursor
+    use_cursor: bool,
+    cursor: Option<Cursor<UserKey>>,
+    include_total: bool,
     search: Option<String>,
+    deleted: bool,
+    sort: Option<UserSort>,
+    order: SortOrder,
+    role: Option<Role>,
+    has_password: Option<bool>,
 }
 
 impl Default for ListUserQuery {
@@ -34,7 +69,82 @@ impl Default for ListUserQuery {
         ListUserQuery {
             limit: 20,
             offset: 0,
+            use_cursor: false,
+            cursor: None,
+            include_total: true,
             search: None,
+            deleted: false,
+            sort: None,
+            order: SortOrder::Asc,
+            role: None,
+            has_password: None,
+        }
+    }
+}
+
+//The key of a user for cursor pagination, which depends on the sort
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum UserKey {
+    Text(String, i32),
+    Time(TimestampKey, i32),
+    Id(i32),
+}
+
+//Users who have never logged in are ordered as if they logged in at one of these times, which
+//keeps them at the end either way
+fn never_logged_in(ascending: bool) -> DateTime<Utc> {
+    if ascending {
+        Utc.ymd(9999, 12, 31).and_hms(0, 0, 0)
+    } else {
+        Utc.ymd(1970, 1, 1).and_hms(0, 0, 0)
+    }
+}
+
+impl UserKey {
+    fn of(user: &User, sort: Option<UserSort>, ascending: bool) -> Self {
+        match sort {
+            Some(UserSort::Name) => UserKey::Text(user.name.to_lowercase(), user.id),
+            Some(UserSort::Email) => UserKey::Text(user.email.clone(), user.id),
+            Some(UserSort::Created) => UserKey::Time(TimestampKey(user.created), user.id),
+            Some(UserSort::LastLogin) => {
+                let t = user
+                    .last_login
+                    .unwrap_or_else(|| never_logged_in(ascending));
+                UserKey::Time(TimestampKey(t), user.id)
+            }
+            None => UserKey::Id(user.id),
+        }
+    }
+
+    fn matches(&self, sort: Option<UserSort>) -> bool {
+        match (self, sort) {
+            (UserKey::Text(..), Some(UserSort::Name)) => true,
+            (UserKey::Text(..), Some(UserSort::Email)) => true,
+            (UserKey::Time(..), Some(UserSort::Created)) => true,
+            (UserKey::Time(..), Some(UserSort::LastLogin)) => true,
+            (UserKey::Id(_), None) => true,
+            _ => false,
+        }
+    }
+
+    fn text(&self) -> Option<(String, i32)> {
+        match self {
+            UserKey::Text(t, id) => Some((t.clone(), *id)),
+            _ => None,
+        }
+    }
+
+    fn time(&self) -> Option<(DateTime<Utc>, i32)> {
+        match self {
+            UserKey::Time(TimestampKey(t), id) => Some((*t, *id)),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> Option<(i32, i32)> {
+        match self {
+            UserKey::Id(id) => Some((*id, *id)),
+            _ => None,
         }
     }
 }
@@ -44,6 +154,11 @@ pub struct UserResponseItem {
     id: i32,
     name: String,
     email: String,
+    role: Role,
+    notify_new_login: bool,
+    deleted_at: Option<i64>,
+    created: i64,
+    last_login: Option<i64>,
 }
 
 impl From<User> for UserResponseItem {
@@ -52,34 +167,133 @@ impl From<User> for UserResponseItem {
             id: u.id,
             name: u.name,
             email: u.email,
+            role: u.role.parse().unwrap(),
+            notify_new_login: u.notify_new_login,
+            deleted_at: u.deleted_at.map(|d| d.timestamp()),
+            created: u.created.timestamp(),
+            last_login: u.last_login.map(|d| d.timestamp()),
         }
     }
 }
 
+//Deleted users are only listed when asked for, so that owners can restore them
+fn filtered_users(query: &ListUserQuery) -> BoxedQuery<'static, Pg> {
+    let mut users = if query.deleted {
+        U::users.filter(U::deleted_at.is_not_null()).into_boxed()
+    } else {
+        U::users.filter(U::deleted_at.is_null()).into_boxed()
+    };
+    if let Some(role) = &query.role {
+        users = users.filter(U::role.eq(role.serialize()));
+    }
+    match query.has_password {
+        Some(true) => users = users.filter(U::password_hash.is_not_null()),
+        Some(false) => users = users.filter(U::password_hash.is_null()),
+        None => {}
+    }
+    if let Some(search) = &query.search {
+        let like = format!("%{}%", escape_like(search));
+        users = users.filter(U::name.ilike(like.clone()).or(U::email.ilike(like)));
+    }
+    users
+}
+
 pub async fn list(
-    _auth: AuthenticatedUser,
+    auth: AuthenticatedUser,
     query: ValidatedQuery<ListUserQuery>,
     state: Data<AppState>,
 ) -> Result<HttpResponse, APIError> {
+    if query.deleted {
+        auth.require(Role::Owner)?;
+    }
     web::block(move || -> Result<_, APIError> {
         let db = state.new_connection();
 
-        let result: CountedLimitResult<User> = match &query.search {
-            None => U::users
+        use SortOrder::*;
+        use UserSort::*;
+        let result = if query.use_cursor || query.cursor.is_some() {
+            //Ordering by relevance doesn't give a key to continue from
+            if query.sort.is_none() && query.search.is_some() {
+                return Err(APIError::BadRequest {
+                    code: "SORT_REQUIRED".to_owned(),
+                    description: Some("Searching with a cursor requires a sort".to_string()),
+                });
+            }
+            if let Some(cursor) = &query.cursor {
+                if !cursor.key.matches(query.sort) {
+                    return Err(APIError::BadRequest {
+                        code: "INVALID_CURSOR".to_owned(),
+                        description: Some("The cursor is for a different sort".to_string()),
+                    });
+                }
+            }
+            let sort = query.sort;
+            let ascending = query.order == Asc;
+            let result: CursorResult<User> = keyset_limit(query.cursor.clone(), query.limit)
+                .with_total(query.include_total)
+                .load(
+                    |u: &User| UserKey::of(u, sort, ascending),
+                    |key, direction, limit| {
+                        let q = filtered_users(&query);
+                        let q = match sort {
+                            Some(Name) => {
+                                let key = key.and_then(UserKey::text);
+                                keyset_order(q, lower(U::name), U::id, ascending, key, direction)
+                            }
+                            Some(Email) => {
+                                let key = key.and_then(UserKey::text);
+                                keyset_order(q, U::email, U::id, ascending, key, direction)
+                            }
+                            Some(Created) => {
+                                let key = key.and_then(UserKey::time);
+                                keyset_order(q, U::created, U::id, ascending, key, direction)
+                            }
+                            Some(LastLogin) => {
+                                let key = key.and_then(UserKey::time);
+                                let last_login =
+                                    coalesce(U::last_login, never_logged_in(ascending));
+                                keyset_order(q, last_login, U::id, ascending, key, direction)
+                            }
+                            //Without a sort the id is the whole key
+                            None => {
+                                let key = key.and_then(UserKey::id);
+                                keyset_order(q, U::id, U::id, true, key, direction)
+                            }
+                        };
+                        q.limit(limit).load::<User>(&db)
+                    },
+                    || filtered_users(&query).count().get_result::<i64>(&db),
+                )?;
+            PageResult::Cursor(result)
+        } else {
+            //Without a sort, search results are ordered by where the term appears
+            let users = filtered_users(&query);
+            let users = match (query.sort, &query.search) {
+                (Some(Name), _) if query.order == Asc => users.order(lower(U::name).asc()),
+                (Some(Name), _) => users.order(lower(U::name).desc()),
+                (Some(Email), _) if query.order == Asc => users.order(U::email.asc()),
+                (Some(Email), _) => users.order(U::email.desc()),
+                (Some(Created), _) if query.order == Asc => users.order(U::created.asc()),
+                (Some(Created), _) => users.order(U::created.desc()),
+                //Users who have never logged in are kept at the end either way
+                (Some(LastLogin), _) if query.order == Asc => {
+                    users.order(U::last_login.asc().nulls_last())
+                }
+                (Some(LastLogin), _) => users.order(U::last_login.desc().nulls_last()),
+                (None, Some(search)) => {
+                    let search = search.to_lowercase();
+                    users
+                        .order(strpos(lower(U::name), search.clone()).asc())
+                        .then_order_by(strpos(lower(U::email), search).asc())
+                }
+                (None, None) => users.order(U::id.asc()),
+            };
+            let result: CountedLimitResult<User> = users
+                .then_order_by(U::id.asc())
                 .counted_limit(query.limit)
                 .offset(query.offset)
-                .load_with_total::<User>(&db)?,
-            Some(search) => {
-                let like = format!("%{}%", search);
-                U::users
-                    .filter(U::name.like(&like))
-                    .or_filter(U::email.like(&like))
-                    .order(strpos(U::name, search).asc())
-                    .then_order_by(strpos(U::email, search).asc())
-                    .counted_limit(query.limit)
-                    .offset(query.offset)
-                    .load_with_total::<User>(&db)?
-            }
+                .load_with_total::<User>(&db)?;
+            PageResult::Counted(result)
         };
 
         Ok(result.map(UserResponseItem::from))
@@ -89,71 +303,65 @@ pub async fn list(
     .await
 }
 
-#[derive(Debug, Deserialize, Validate)]
-pub struct CreateUserForm {
-    #[validate(length(min = 1, max = 255))]
-    name: String,
-    #[validate(email)]
-    email: String,
-}
-
-fn assert_email_available(db: &Connection, email: &String) -> Result<(), APIError> {
+//There must always be at least one owner able to manage the other accounts
+fn assert_not_last_owner(db: &Connection, user: &User) -> Result<(), APIError> {
+    if user.role != Role::Owner.serialize() {
+        return Ok(());
+    }
     let count = U::users
-        .filter(lower(U::email).eq(email.to_ascii_lowercase()))
+        .filter(U::role.eq(Role::Owner.serialize()))
+        .filter(U::id.ne(user.id))
+        .filter(U::deleted_at.is_null())
         .count()
         .get_result::<i64>(db)?;
     if count > 0 {
+        Ok(())
+    } else {
         Err(APIError::BadRequest {
-            code: "EMAIL_TAKEN".to_owned(),
-            description: Some("The Email address is already in use".to_string()),
+            code: "LAST_OWNER".to_owned(),
+            description: Some("There must be at least one owner".to_string()),
         })
-    } else {
-        Ok(())
     }
 }
 
-pub async fn create(
-    _auth: AuthenticatedUser,
-    form: ValidatedForm<CreateUserForm>,
-    state: Data<AppState>,
-) -> Result<HttpResponse, APIError> {
-    web::block(move || -> Result<UserResponseItem, APIError> {
-        let db = state.new_connection();
-        assert_email_available(&db, &form.email)?;
-
-        let reset = generate_token(AUTH_TOKEN_BYTES);
-        let insert = NewUser {
-            name: form.name.clone(),
-            email: form.email.clone().to_ascii_lowercase(),
-            password_hash: None,
-            password_reset_token: Some(reset.clone()),
-        };
-        let user: User = diesel::insert_into(U::users)
-            .values(&insert)
-            .get_result(&db)?;
-
-        match send_reset_email(&state.settings, &user.email, &reset) {
-            Ok(_) => {}
-            Err(e) => log::warn!("Unable to send reset email on account creation: {}", e),
-        }
-
-        Ok(user.into())
-    })
-    .map_ok(ok_json)
-    .err_into()
-    .await
+//Soft deleted users keep their email address, as it is still unique in the database
+pub fn assert_email_available(db: &Connection, email: &str) -> Result<(), APIError> {
+    let existing: Option<Option<DateTime<Utc>>> = U::users
+        .filter(lower(U::email).eq(email.to_ascii_lowercase()))
+        .select(U::deleted_at)
+        .first(db)
+        .optional()?;
+    match existing {
+        None => Ok(()),
+        Some(None) => Err(APIError::BadRequest {
+            code: "EMAIL_TAKEN".to_owned(),
+            description: Some("The Email address is already in use".to_string()),
+        }),
+        Some(Some(_)) => Err(APIError::BadRequest {
+            code: "EMAIL_BELONGS_TO_DELETED_USER".to_owned(),
+            description: Some(
+                "The Email address belongs to a deleted user, who must be restored or purged first"
+                    .to_string(),
+            ),
+        }),
+    }
 }
 
+//Accessing a user other than the logged in user requires the given role
 fn resolve_user(
     auth: &AuthenticatedUser,
     user_id: i32,
     conn: &Connection,
+    role: Role,
 ) -> Result<User, APIError> {
-    //If this isn't the logged in user - check that it exists and for admin
     if user_id == auth.user_id() {
         Ok(auth.user.clone())
     } else {
-        let user = U::users.find(user_id).get_result::<User>(conn)?;
+        auth.require(role)?;
+        let user = U::users
+            .find(user_id)
+            .filter(U::deleted_at.is_null())
+            .get_result::<User>(conn)?;
         Ok(user)
     }
 }
@@ -165,7 +373,7 @@ pub async fn get(
 ) -> Result<HttpResponse, APIError> {
     web::block(move || -> Result<UserResponseItem, APIError> {
         let db = state.new_connection();
-        let user = resolve_user(&auth, user_id.into_inner(), &db)?;
+        let user = resolve_user(&auth, user_id.into_inner(), &db, Role::Viewer)?;
         Ok(user.into())
     })
     .map_ok(ok_json)
@@ -179,6 +387,8 @@ pub struct UpdateUserForm {
     name: Option<String>,
     #[validate(email)]
     email: Option<String>,
+    role: Option<Role>,
+    notify_new_login: Option<bool>,
 }
 
 pub async fn update(
@@ -190,7 +400,8 @@ pub async fn update(
     web::block(move || -> Result<UserResponseItem, APIError> {
         let db = state.new_connection();
         let user_id = user_id.into_inner();
-        let mut user = resolve_user(&auth, user_id, &db)?;
+        let mut user = resolve_user(&auth, user_id, &db, Role::Owner)?;
+        let before = UserResponseItem::from(user.clone());
 
         match &form.name {
             Some(n) => {
@@ -198,26 +409,70 @@ pub async fn update(
             }
             _ => {}
         }
-        match &form.email {
-            Some(e) => {
-                let e = e.to_ascii_lowercase();
-                if e.as_str() != &user.email.to_ascii_lowercase() {
-                    assert_email_available(&db, &e)?;
-                    user.email = e;
+        //A new email address has to be confirmed before it replaces the current one
+        let new_email = form
+            .email
+            .as_ref()
+            .map(|e| e.to_ascii_lowercase())
+            .filter(|e| e != &user.email.to_ascii_lowercase());
+        match &form.role {
+            Some(r) => {
+                if r.serialize() != user.role {
+                    auth.require(Role::Owner)?;
+                    assert_not_last_owner(&db, &user)?;
+                    user.role = r.serialize();
                 }
             }
             _ => {}
         }
 
-        diesel::update(&user).set(&user).execute(&db)?;
+        if let Some(n) = form.notify_new_login {
+            user.notify_new_login = n;
+        }
 
-        Ok(user.into())
+        let after = UserResponseItem::from(user.clone());
+        let change_token = db.transaction::<_, APIError, _>(|| {
+            diesel::update(&user).set(&user).execute(&db)?;
+            let token = match &new_email {
+                Some(e) => {
+                    let source = EventSource::from(&auth.session);
+                    Some(request_email_change(
+                        &db,
+                        &state.settings,
+                        &user,
+                        e,
+                        &source,
+                    )?)
+                }
+                None => None,
+            };
+            record_change(
+                &db,
+                &auth,
+                AuditTarget::User,
+                user.id,
+                Some(&before),
+                Some(&after),
+            )?;
+            Ok(token)
+        })?;
+        //Only sent once committed, so the link can't refer to a change that was rolled back.
+        //The other changes have been saved by now, so a failure is logged rather than returned
+        //and the email change can be requested again
+        if let (Some(e), Some(token)) = (&new_email, &change_token) {
+            if let Err(e) = send_email_change(&state.settings, &user.email, e, token) {
+                log::warn!("Unable to send email change confirmation: {:?}", e);
+            }
+        }
+
+        Ok(after)
     })
     .map_ok(ok_json)
     .err_into()
     .await
 }
 
+//Users are only marked as deleted so that they can be restored, until they are purged
 pub async fn delete(
     auth: AuthenticatedUser,
     user_id: Path<i32>,
@@ -225,16 +480,261 @@ pub async fn delete(
 ) -> Result<HttpResponse, APIError> {
     web::block(move || -> Result<(), APIError> {
         let db = state.new_connection();
-        let user = resolve_user(&auth, user_id.into_inner(), &db)?;
+        let user = resolve_user(&auth, user_id.into_inner(), &db, Role::Owner)?;
+        assert_not_last_owner(&db, &user)?;
         let user_id = user.id;
 
+        use crate::schema::email_changes::dsl as EC;
+        use crate::schema::login_challenges::dsl as LC;
+        use crate::schema::magic_link_tokens::dsl as M;
+        use crate::schema::password_reset_tokens::dsl as P;
         use crate::schema::sessions::dsl as S;
-        diesel::delete(S::sessions.filter(S::user_id.eq(user_id))).execute(&db)?;
-        diesel::delete(U::users.filter(U::id.eq(user_id))).execute(&db)?;
+        use crate::schema::webauthn_challenges::dsl as WCh;
+        let before = UserResponseItem::from(user.clone());
+        db.transaction::<_, APIError, _>(|| {
+            record_change(&db, &auth, AuditTarget::User, user_id, Some(&before), None)?;
+            diesel::update(&user)
+                .set(U::deleted_at.eq(diesel::dsl::now))
+                .execute(&db)?;
+            //Log the user out and invalidate anything that could be used to log back in
+            diesel::delete(S::sessions.filter(S::user_id.eq(user_id))).execute(&db)?;
+            diesel::delete(LC::login_challenges.filter(LC::user_id.eq(user_id))).execute(&db)?;
+            diesel::delete(EC::email_changes.filter(EC::user_id.eq(user_id))).execute(&db)?;
+            diesel::delete(M::magic_link_tokens.filter(M::user_id.eq(user_id))).execute(&db)?;
+            diesel::delete(P::password_reset_tokens.filter(P::user_id.eq(user_id))).execute(&db)?;
+            diesel::delete(WCh::webauthn_challenges.filter(WCh::user_id.eq(user_id)))
+                .execute(&db)?;
+            Ok(())
+        })?;
+
+        Ok(())
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+pub async fn restore(
+    auth: AuthenticatedUser,
+    user_id: Path<i32>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    auth.require(Role::Owner)?;
+    web::block(move || -> Result<UserResponseItem, APIError> {
+        let db = state.new_connection();
+        let user: User = U::users
+            .find(user_id.into_inner())
+            .filter(U::deleted_at.is_not_null())
+            .get_result(&db)?;
+
+        let user: User = db.transaction::<_, APIError, _>(|| {
+            let user: User = diesel::update(&user)
+                .set(U::deleted_at.eq(None::<DateTime<Utc>>))
+                .get_result(&db)?;
+            let after = UserResponseItem::from(user.clone());
+            record_restore(&db, &auth, AuditTarget::User, user.id, &after)?;
+            Ok(user)
+        })?;
+
+        Ok(user.into())
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ChangePasswordForm {
+    current_password: String,
+    new_password: String,
+    #[serde(default)]
+    logout_other_sessions: bool,
+}
+
+//Users can only change their own password, and must know the current one
+pub async fn change_password(
+    auth: AuthenticatedUser,
+    user_id: Path<i32>,
+    form: ValidatedForm<ChangePasswordForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    if user_id.into_inner() != auth.user_id() {
+        return Err(APIError::Forbidden);
+    }
+    web::block(move || -> Result<(), APIError> {
+        let db = state.new_connection();
+        let user = &auth.user;
+
+        //Wrong guesses count towards the lockout, so a stolen session can't be used to find it
+        assert_not_locked(user)?;
+        let correct = match &user.password_hash {
+            Some(hashed) => verify_password(hashed, &form.current_password)?,
+            None => false,
+        };
+        let source = EventSource::from(&auth.session);
+        if !correct {
+            count_failed_attempt(&db, &state.settings, user)?;
+            record_event(
+                &db,
+                user.id,
+                SecurityEventKind::PasswordChanged,
+                false,
+                &source,
+            )?;
+            return Err(APIError::BadRequest {
+                code: "INCORRECT_PASSWORD".to_owned(),
+                description: Some("The current password is incorrect".to_string()),
+            });
+        }
+
+        let policy = &state.settings.password_policy;
+        check_password_policy(policy, &form.new_password, &user.name, &user.email)?;
+        let hashed = hash_password(&state.settings.password_hashing, &form.new_password)?;
+        db.transaction::<_, APIError, _>(|| {
+            diesel::update(user)
+                .set(U::password_hash.eq(hashed))
+                .execute(&db)?;
+            if form.logout_other_sessions {
+                use crate::schema::sessions::dsl as S;
+                diesel::delete(
+                    S::sessions
+                        .filter(S::user_id.eq(user.id))
+                        .filter(S::id.ne(auth.session.id)),
+                )
+                .execute(&db)?;
+            }
+            record_event(
+                &db,
+                user.id,
+                SecurityEventKind::PasswordChanged,
+                true,
+                &source,
+            )?;
+            Ok(())
+        })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+//Logs a user out everywhere, e.g. when someone leaves the company
+pub async fn delete_sessions(
+    auth: AuthenticatedUser,
+    user_id: Path<i32>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    web::block(move || -> Result<(), APIError> {
+        let db = state.new_connection();
+        let user = resolve_user(&auth, user_id.into_inner(), &db, Role::Owner)?;
 
+        use crate::schema::sessions::dsl as S;
+        diesel::delete(S::sessions.filter(S::user_id.eq(user.id))).execute(&db)?;
+        let source = EventSource::from(&auth.session);
+        record_event(
+            &db,
+            user.id,
+            SecurityEventKind::SessionRevoked,
+            true,
+            &source,
+        )?;
         Ok(())
     })
     .map_ok(ok_json)
     .err_into()
     .await
 }
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct ListSecurityEventsQuery {
+    #[validate(range(min = 1, max = 100))]
+    limit: i64,
+    offset: i64,
+    //Cursor pagination is used when asked for, or when continuing from a cursor
+    use_cursor: bool,
+    cursor: Option<Cursor<(TimestampKey, i32)>>,
+    include_total: bool,
+}
+
+impl Default for ListSecurityEventsQuery {
+    fn default() -> Self {
+        ListSecurityEventsQuery {
+            limit: 20,
+            offset: 0,
+            use_cursor: false,
+            cursor: None,
+            include_total: true,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SecurityEventResponseItem {
+    id: i32,
+    kind: SecurityEventKind,
+    success: bool,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    created: i64,
+}
+
+impl From<SecurityEvent> for SecurityEventResponseItem {
+    fn from(e: SecurityEvent) -> Self {
+        SecurityEventResponseItem {
+            id: e.id,
+            kind: e.kind.parse().unwrap(),
+            success: e.success,
+            ip: e.ip.map(|ip| ip.ip().to_string()),
+            user_agent: e.user_agent,
+            created: e.created.timestamp(),
+        }
+    }
+}
+
+//Most recent first, so that owners can audit who has been accessing an account
+pub async fn security_events(
+    auth: AuthenticatedUser,
+    user_id: Path<i32>,
+    query: ValidatedQuery<ListSecurityEventsQuery>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let user = resolve_user(&auth, user_id.into_inner(), &db, Role::Owner)?;
+
+        let result = if query.use_cursor || query.cursor.is_some() {
+            let result: CursorResult<SecurityEvent> =
+                keyset_limit(query.cursor.clone(), query.limit)
+                    .with_total(query.include_total)
+                    .load(
+                        |e: &SecurityEvent| (TimestampKey(e.created), e.id),
+                        |key, direction, limit| {
+                            let q = SecurityEvent::belonging_to(&user).into_boxed();
+                            let key = key.map(|&(TimestampKey(t), id)| (t, id));
+                            keyset_order(q, SE::created, SE::id, false, key, direction)
+                                .limit(limit)
+                                .load::<SecurityEvent>(&db)
+                        },
+                        || {
+                            SecurityEvent::belonging_to(&user)
+                                .count()
+                                .get_result::<i64>(&db)
+                        },
+                    )?;
+            PageResult::Cursor(result)
+        } else {
+            let result: CountedLimitResult<SecurityEvent> = SecurityEvent::belonging_to(&user)
+                .order((SE::created.desc(), SE::id.desc()))
+                .counted_limit(query.limit)
+                .offset(query.offset)
+                .load_with_total::<SecurityEvent>(&db)?;
+            PageResult::Counted(result)
+        };
+
+        Ok(result.map(SecurityEventResponseItem::from))
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
@@ -0,0 +1,347 @@
+use crate::api::actix::{ClientIpExt, HeaderMapExt};
+use crate::api::auth::AuthenticatedUser;
+use crate::api::errors::APIError;
+use crate::api::ok_json;
+use crate::api::routes::session::{
+    assert_not_locked, clear_failed_logins, record_failed_login, start_session, AUTH_TOKEN_BYTES,
+};
+use crate::api::routes::tokens::check_claim;
+use crate::api::security_events::EventSource;
+use crate::api::token::{generate_token, hash_token};
+use crate::api::webauthn::{decoy_credential, webauthn};
+use crate::ext::postgres::functions::*;
+use crate::models::{User, WebauthnChallenge, WebauthnCredential};
+use crate::schema::users::dsl as U;
+use crate::schema::webauthn_challenges::dsl as WCh;
+use crate::schema::webauthn_credentials::dsl as WCr;
+use crate::state::{AppState, Connection};
+use actix_validated_forms::form::ValidatedForm;
+use actix_web::web::{Data, Json, Path};
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use futures::TryFutureExt;
+use ipnetwork::IpNetwork;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+use webauthn_rs::proto::{
+    CreationChallengeResponse, Credential, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse,
+};
+use webauthn_rs::{AuthenticationState, RegistrationState};
+
+const REGISTER: &str = "REGISTER";
+const AUTHENTICATE: &str = "AUTHENTICATE";
+
+//The ceremony state is kept server side, the client only receives a token referring to it
+fn store_challenge<T: Serialize>(
+    db: &Connection,
+    user_id: i32,
+    kind: &str,
+    state: &T,
+) -> Result<String, APIError> {
+    let token = generate_token(AUTH_TOKEN_BYTES);
+    diesel::insert_into(WCh::webauthn_challenges)
+        .values((
+            WCh::user_id.eq(user_id),
+            WCh::token_hash.eq(hash_token(&token)),
+            WCh::kind.eq(kind),
+            WCh::state.eq(serde_json::to_string(state).unwrap()),
+        ))
+        .execute(db)?;
+    Ok(token)
+}
+
+const PASSKEY_CHALLENGE: &str = "passkey challenge";
+
+//Challenges can only be used once, this should run in the same transaction as the work
+//done with the ceremony state
+fn take_challenge<T: DeserializeOwned>(
+    db: &Connection,
+    state: &AppState,
+    token: &str,
+    kind: &str,
+) -> Result<(User, T), APIError> {
+    let cutoff = state.settings.sessions.login_challenge_cutoff();
+    let (challenge, user): (WebauthnChallenge, User) = match WCh::webauthn_challenges
+        .inner_join(U::users)
+        .filter(U::deleted_at.is_null())
+        .filter(WCh::token_hash.eq(hash_token(token)))
+        .filter(WCh::kind.eq(kind))
+        .filter(WCh::created.ge(cutoff))
+        .first::<(WebauthnChallenge, User)>(db)
+        .optional()?
+    {
+        Some(r) => r,
+        None => return Err(APIError::IncorrectCredentials),
+    };
+    let claimed = diesel::delete(
+        WCh::webauthn_challenges
+            .filter(WCh::id.eq(challenge.id))
+            .filter(WCh::created.ge(cutoff)),
+    )
+    .execute(db)?;
+    let expired = challenge.created < state.settings.sessions.login_challenge_cutoff();
+    check_claim(claimed, expired, PASSKEY_CHALLENGE)?;
+    let ceremony = serde_json::from_str::<T>(&challenge.state)
+        .map_err(|e| APIError::InternalError(format!("{}", e)))?;
+    Ok((user, ceremony))
+}
+
+fn load_credentials(db: &Connection, user: &User) -> Result<Vec<Credential>, APIError> {
+    WebauthnCredential::belonging_to(user)
+        .load::<WebauthnCredential>(db)?
+        .into_iter()
+        .map(|c| {
+            serde_json::from_str::<Credential>(&c.credential)
+                .map_err(|e| APIError::InternalError(format!("{}", e)))
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct RegisterStartResponse {
+    challenge: String,
+    options: CreationChallengeResponse,
+}
+
+pub async fn register_start(
+    auth: AuthenticatedUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let wan = webauthn(&state.settings)?;
+        let (options, reg_state) = wan.generate_challenge_register(&auth.user.email, false)?;
+        let challenge = store_challenge(&db, auth.user_id(), REGISTER, &reg_state)?;
+        Ok(RegisterStartResponse { challenge, options })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+//The credential is nested so this is sent as JSON, rather than as a form
+#[derive(Deserialize, Validate)]
+pub struct RegisterFinishForm {
+    challenge: String,
+    #[validate(length(min = 1, max = 255))]
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Serialize)]
+struct CredentialResponseItem {
+    id: i32,
+    name: String,
+    created: i64,
+    last_used: Option<i64>,
+}
+
+impl From<WebauthnCredential> for CredentialResponseItem {
+    fn from(c: WebauthnCredential) -> Self {
+        CredentialResponseItem {
+            id: c.id,
+            name: c.name,
+            created: c.created.timestamp(),
+            last_used: c.last_used.map(|t| t.timestamp()),
+        }
+    }
+}
+
+pub async fn register_finish(
+    auth: AuthenticatedUser,
+    form: Json<RegisterFinishForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    form.validate()?;
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let wan = webauthn(&state.settings)?;
+        db.transaction::<_, APIError, _>(|| {
+            let (user, reg_state): (User, RegistrationState) =
+                take_challenge(&db, &state, &form.challenge, REGISTER)?;
+            if user.id != auth.user_id() {
+                return Err(APIError::Forbidden);
+            }
+            let (credential, _) = wan.register_credential(&form.credential, &reg_state, |id| {
+                WCr::webauthn_credentials
+                    .filter(WCr::credential_id.eq(base64::encode(id)))
+                    .count()
+                    .get_result::<i64>(&db)
+                    .map(|c| c > 0)
+                    .map_err(|_| ())
+            })?;
+            let created: WebauthnCredential = diesel::insert_into(WCr::webauthn_credentials)
+                .values((
+                    WCr::user_id.eq(user.id),
+                    WCr::name.eq(&form.name),
+                    WCr::credential_id.eq(base64::encode(&credential.cred_id)),
+                    WCr::credential.eq(serde_json::to_string(&credential).unwrap()),
+                ))
+                .get_result(&db)?;
+            Ok(CredentialResponseItem::from(created))
+        })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct LoginStartForm {
+    #[validate(email)]
+    email: String,
+}
+
+#[derive(Serialize)]
+struct LoginStartResponse {
+    challenge: String,
+    options: RequestChallengeResponse,
+}
+
+//Unknown accounts and those without passkeys are given a challenge that can never succeed,
+//so that the response doesn't reveal either
+pub async fn login_start(
+    form: ValidatedForm<LoginStartForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let wan = webauthn(&state.settings)?;
+        let user: Option<User> = U::users
+            .filter(lower(U::email).eq(&form.email.to_ascii_lowercase()))
+            .filter(U::deleted_at.is_null())
+            .first::<User>(&db)
+            .optional()?;
+        let credentials = match &user {
+            Some(u) => load_credentials(&db, u)?,
+            None => Vec::new(),
+        };
+        let has_credentials = !credentials.is_empty();
+        let (mut options, auth_state) = wan.generate_challenge_authenticate(credentials)?;
+        let challenge = match user {
+            Some(user) if has_credentials => {
+                store_challenge(&db, user.id, AUTHENTICATE, &auth_state)?
+            }
+            _ => {
+                options.public_key.allow_credentials =
+                    vec![decoy_credential(&state.decoy_key, &form.email)];
+                generate_token(AUTH_TOKEN_BYTES)
+            }
+        };
+        Ok(LoginStartResponse { challenge, options })
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct LoginFinishForm {
+    challenge: String,
+    credential: PublicKeyCredential,
+}
+
+//A passkey is sufficient on its own, so this issues a session without a TOTP challenge
+pub async fn login_finish(
+    form: Json<LoginFinishForm>,
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, APIError> {
+    let ip_addr = req.client_ip(&state.settings.app.trusted_proxies)?;
+    let ip_net = IpNetwork::from(ip_addr);
+    let ua_opt = req.headers().user_agent();
+
+    web::block(move || {
+        let db = state.new_connection();
+        let wan = webauthn(&state.settings)?;
+
+        let user_agent = match ua_opt {
+            Some(t) => t,
+            None => return Err(APIError::BadAgent),
+        };
+
+        let response = db.transaction::<_, APIError, _>(|| {
+            let (user, auth_state): (User, AuthenticationState) =
+                take_challenge(&db, &state, &form.challenge, AUTHENTICATE)?;
+            assert_not_locked(&user)?;
+            let (cred_id, auth_data) =
+                match wan.authenticate_credential(&form.credential, &auth_state) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        let source = EventSource {
+                            ip: Some(ip_net),
+                            user_agent: Some(user_agent.clone()),
+                        };
+                        record_failed_login(&db, &state.settings, &user, &source)?;
+                        //Committed along with the claim, so the challenge can't be retried
+                        return Ok(Err(e.into()));
+                    }
+                };
+
+            //Keep the signature counter up to date to detect cloned authenticators
+            let stored: WebauthnCredential = WebauthnCredential::belonging_to(&user)
+                .filter(WCr::credential_id.eq(base64::encode(&cred_id)))
+                .first(&db)?;
+            let mut credential = serde_json::from_str::<Credential>(&stored.credential)
+                .map_err(|e| APIError::InternalError(format!("{}", e)))?;
+            credential.counter = auth_data.counter;
+            diesel::update(&stored)
+                .set((
+                    WCr::credential.eq(serde_json::to_string(&credential).unwrap()),
+                    WCr::last_used.eq(diesel::dsl::now),
+                ))
+                .execute(&db)?;
+
+            clear_failed_logins(&db, &user)?;
+            let session = start_session(&db, &state.settings, user, ip_net, user_agent.clone())?;
+            Ok(Ok(session))
+        })?;
+        let (response, notice) = response?;
+        if let Some(n) = notice {
+            n.send(&state.settings);
+        }
+        Ok(response)
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+pub async fn list_credentials(
+    auth: AuthenticatedUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let credentials = WebauthnCredential::belonging_to(&auth.user)
+            .order(WCr::created.asc())
+            .load::<WebauthnCredential>(&db)?
+            .into_iter()
+            .map(CredentialResponseItem::from)
+            .collect::<Vec<_>>();
+        Ok(credentials)
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
+
+pub async fn delete_credential(
+    auth: AuthenticatedUser,
+    credential_id: Path<i32>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    web::block(move || -> Result<_, APIError> {
+        let db = state.new_connection();
+        let credential: WebauthnCredential = WebauthnCredential::belonging_to(&auth.user)
+            .filter(WCr::id.eq(credential_id.into_inner()))
+            .first::<WebauthnCredential>(&db)?;
+        diesel::delete(&credential).execute(&db)?;
+        Ok(())
+    })
+    .map_ok(ok_json)
+    .err_into()
+    .await
+}
@@ -0,0 +1,78 @@
+use crate::api::actix::{ClientIpExt, HeaderMapExt};
+use crate::models::{NewSecurityEvent, Session};
+use crate::schema::security_events::dsl as SE;
+use crate::state::Connection;
+use actix_web::HttpRequest;
+use diesel::prelude::*;
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SecurityEventKind {
+    Login,
+    Logout,
+    PasswordChanged,
+    PasswordResetRequested,
+    PasswordReset,
+    SessionRevoked,
+    EmailChangeRequested,
+    EmailChanged,
+}
+
+impl FromStr for SecurityEventKind {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_plain::from_str::<Self>(s).map_err(|_| ())
+    }
+}
+
+impl SecurityEventKind {
+    pub fn serialize(&self) -> String {
+        serde_plain::to_string(&self).unwrap()
+    }
+}
+
+// The client that caused an event
+pub struct EventSource {
+    pub ip: Option<IpNetwork>,
+    pub user_agent: Option<String>,
+}
+
+impl EventSource {
+    pub fn from_request(req: &HttpRequest, trusted_proxies: &[IpNetwork]) -> Self {
+        EventSource {
+            ip: req.client_ip(trusted_proxies).ok().map(IpNetwork::from),
+            user_agent: req.headers().user_agent(),
+        }
+    }
+}
+
+impl From<&Session> for EventSource {
+    fn from(s: &Session) -> Self {
+        EventSource {
+            ip: Some(s.last_ip),
+            user_agent: Some(s.user_agent.clone()),
+        }
+    }
+}
+
+pub fn record_event(
+    db: &Connection,
+    user_id: i32,
+    kind: SecurityEventKind,
+    success: bool,
+    source: &EventSource,
+) -> QueryResult<()> {
+    diesel::insert_into(SE::security_events)
+        .values(&NewSecurityEvent {
+            user_id,
+            kind: kind.serialize(),
+            success,
+            ip: source.ip,
+            user_agent: source.user_agent.clone(),
+        })
+        .execute(db)?;
+    Ok(())
+}
@@ -1,8 +1,14 @@
 use rand::distributions::Standard;
 use rand::Rng;
+use sha2::{Digest, Sha256};
 
 pub fn generate_token(length: u8) -> String {
     let rng = rand::thread_rng();
     let v: Vec<u8> = rng.sample_iter(&Standard).take(length as usize).collect();
     base64::encode(&v)
 }
+
+// Tokens are only stored as a digest so that they can't be used from a database dump
+pub fn hash_token(token: &str) -> String {
+    base64::encode(Sha256::digest(token.as_bytes()))
+}
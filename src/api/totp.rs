@@ -0,0 +1,164 @@
+use crate::api::errors::APIError;
+use crate::api::token::hash_token;
+use crate::models::User;
+use crate::schema::recovery_codes::dsl as RC;
+use crate::schema::users::dsl as U;
+use crate::state::Connection;
+use base32::Alphabet;
+use chrono::Utc;
+use diesel::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use rand::distributions::{Alphanumeric, Standard};
+use rand::Rng;
+use sha1::Sha1;
+use url::Url;
+
+// https://tools.ietf.org/html/rfc6238
+const SECRET_BYTES: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+const ALLOWED_SKEW: i64 = 1;
+const ISSUER: &str = "KiwiJoinery";
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+// A new base32 encoded secret
+pub fn generate_secret() -> String {
+    let rng = rand::thread_rng();
+    let v: Vec<u8> = rng.sample_iter(&Standard).take(SECRET_BYTES).collect();
+    base32::encode(ALPHABET, &v)
+}
+
+// URI to be displayed as a QR code by the client for authenticator apps
+pub fn provisioning_uri(secret: &str, account: &str) -> Url {
+    let mut url = Url::parse("otpauth://totp/").unwrap();
+    url.set_path(format!("{}:{}", ISSUER, account).as_str());
+    url.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", ISSUER)
+        .append_pair("digits", DIGITS.to_string().as_str())
+        .append_pair("period", STEP_SECONDS.to_string().as_str());
+    url
+}
+
+fn code_at(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(secret).unwrap();
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | (hash[offset + 3] as u32);
+    binary % 10u32.pow(DIGITS)
+}
+
+// The time step matching a code, allowing for some clock skew. Steps up to and including
+// `last_step` have already been used, so are never matched again.
+fn matching_step(secret: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let secret = base32::decode(ALPHABET, secret)?;
+    let code: u32 = code.trim().parse().ok()?;
+    let counter = Utc::now().timestamp() / STEP_SECONDS;
+    (-ALLOWED_SKEW..=ALLOWED_SKEW)
+        .map(|skew| counter + skew)
+        .filter(|step| last_step.map_or(true, |last| *step > last))
+        .find(|step| code_at(&secret, *step as u64) == code)
+}
+
+// Checks a TOTP code against the given secret, which may not have been confirmed yet. The step
+// is only recorded if no later one has been accepted since, so a code can't be replayed even
+// by requests made at the same time.
+pub fn verify_code(db: &Connection, user: &User, secret: &str, code: &str) -> QueryResult<bool> {
+    let step = match matching_step(secret, code, user.totp_last_step) {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+    let updated = diesel::update(
+        U::users
+            .find(user.id)
+            .filter(U::totp_last_step.is_null().or(U::totp_last_step.lt(step))),
+    )
+    .set(U::totp_last_step.eq(step))
+    .execute(db)?;
+    Ok(updated > 0)
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(RECOVERY_CODE_LENGTH)
+                .collect::<String>()
+                .to_ascii_lowercase()
+        })
+        .collect()
+}
+
+// Replaces any existing recovery codes for the user, only the hashes are stored
+pub fn store_recovery_codes(db: &Connection, user: &User, codes: &[String]) -> QueryResult<()> {
+    diesel::delete(RC::recovery_codes.filter(RC::user_id.eq(user.id))).execute(db)?;
+    let rows = codes
+        .iter()
+        .map(|c| (RC::user_id.eq(user.id), RC::code_hash.eq(hash_token(c))))
+        .collect::<Vec<_>>();
+    diesel::insert_into(RC::recovery_codes)
+        .values(&rows)
+        .execute(db)?;
+    Ok(())
+}
+
+// Accepts either a TOTP code, or a recovery code which can then no longer be used
+pub fn verify_user_code(db: &Connection, user: &User, code: &str) -> Result<bool, APIError> {
+    let secret = match (&user.totp_secret, user.totp_enabled) {
+        (Some(s), true) => s,
+        _ => return Ok(false),
+    };
+    if verify_code(db, user, secret, code)? {
+        return Ok(true);
+    }
+    let used = diesel::delete(
+        RC::recovery_codes
+            .filter(RC::user_id.eq(user.id))
+            .filter(RC::code_hash.eq(hash_token(&code.trim().to_ascii_lowercase()))),
+    )
+    .execute(db)?;
+    Ok(used > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // https://tools.ietf.org/html/rfc6238#appendix-B (SHA-1), truncated to 6 digits
+    #[test]
+    fn code_at_matches_rfc_test_vectors() {
+        let secret = b"12345678901234567890";
+        let vectors: [(u64, u32); 6] = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+        for (time, expected) in vectors.iter() {
+            let counter = time / STEP_SECONDS as u64;
+            assert_eq!(code_at(secret, counter), expected % 10u32.pow(DIGITS));
+        }
+    }
+
+    #[test]
+    fn matching_step_rejects_used_steps() {
+        let raw = b"12345678901234567890";
+        let secret = base32::encode(ALPHABET, raw);
+        let step = Utc::now().timestamp() / STEP_SECONDS;
+        let code = format!("{:06}", code_at(raw, step as u64));
+        assert_eq!(matching_step(&secret, &code, None), Some(step));
+        assert_eq!(matching_step(&secret, &code, Some(step - 1)), Some(step));
+        assert_eq!(matching_step(&secret, &code, Some(step)), None);
+    }
+}
@@ -0,0 +1,43 @@
+use crate::api::errors::APIError;
+use crate::settings::{Settings, WebAuthn};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use url::Url;
+use webauthn_rs::base64_data::Base64UrlSafeData;
+use webauthn_rs::proto::AllowCredentials;
+use webauthn_rs::{Webauthn, WebauthnConfig};
+
+impl WebauthnConfig for WebAuthn {
+    fn get_relying_party_name(&self) -> &str {
+        &self.rp_name
+    }
+
+    fn get_origin(&self) -> &Url {
+        &self.origin
+    }
+
+    fn get_relying_party_id(&self) -> &str {
+        &self.rp_id
+    }
+}
+
+// Passkeys are disabled unless the relying party has been configured
+pub fn webauthn(settings: &Settings) -> Result<Webauthn<WebAuthn>, APIError> {
+    match &settings.webauthn {
+        Some(config) => Ok(Webauthn::new(config.clone())),
+        None => Err(APIError::NotImplemented),
+    }
+}
+
+// Offered for accounts that don't exist or have no passkeys. It is derived from the email so
+// it stays the same between requests, and keyed so it can't be told apart from a real one.
+pub fn decoy_credential(key: &[u8], email: &str) -> AllowCredentials {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.update(email.to_ascii_lowercase().as_bytes());
+    let id = mac.finalize().into_bytes().to_vec();
+    AllowCredentials {
+        type_: "public-key".to_owned(),
+        id: Base64UrlSafeData(id),
+        transports: None,
+    }
+}
@@ -0,0 +1,326 @@
+use crate::ext::postgres::limit::CountedLimitResult;
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::dsl;
+use diesel::expression::{AppearsOnTable, AsExpression, NonAggregate};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::query_builder::{BoxedSelectStatement, QueryFragment};
+use diesel::sql_types::{Bool, Integer};
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Keyset pagination, a page is found relative to the key of a row that has already been
+// seen rather than by an offset. Unlike CountedLimit this stays fast as the table grows and
+// pages don't shift when rows are inserted. It is opt-in for listings that use CountedLimit,
+// as the response has a different shape.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    //Rows after the key, in the listing's order
+    Forward,
+    //Rows before the key, in reverse order
+    Backward,
+}
+
+//Opaque to clients, the key and direction are encoded as url safe base64 JSON
+#[derive(Debug, Clone)]
+pub struct Cursor<K> {
+    pub key: K,
+    pub direction: Direction,
+}
+
+impl<K: Serialize> Cursor<K> {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(&(&self.key, self.direction)).unwrap();
+        base64::encode_config(&json, base64::URL_SAFE_NO_PAD)
+    }
+}
+
+impl<K: DeserializeOwned> Cursor<K> {
+    pub fn decode(s: &str) -> Option<Self> {
+        let json = base64::decode_config(s, base64::URL_SAFE_NO_PAD).ok()?;
+        let (key, direction) = serde_json::from_slice(&json).ok()?;
+        Some(Cursor { key, direction })
+    }
+}
+
+impl<'de, K: DeserializeOwned> Deserialize<'de> for Cursor<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Cursor::decode(&s).ok_or_else(|| D::Error::custom("invalid cursor"))
+    }
+}
+
+//A timestamp column in a key, stored as microseconds which is the precision Postgres keeps
+#[derive(Debug, Clone, Copy)]
+pub struct TimestampKey(pub DateTime<Utc>);
+
+impl Serialize for TimestampKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let micros = self.0.timestamp() * 1_000_000 + i64::from(self.0.timestamp_subsec_micros());
+        serializer.serialize_i64(micros)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimestampKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let micros = i64::deserialize(deserializer)?;
+        let secs = micros.div_euclid(1_000_000);
+        let nanos = (micros.rem_euclid(1_000_000) * 1_000) as u32;
+        match Utc.timestamp_opt(secs, nanos).single() {
+            Some(t) => Ok(TimestampKey(t)),
+            None => Err(D::Error::custom("invalid timestamp")),
+        }
+    }
+}
+
+//Orders a query by a column with an id column to break ties, and filters it to the rows past
+//the key in the given direction. Moving backward reverses the order, so that the rows nearest
+//the key are fetched first
+pub fn keyset_order<'a, ST, QS, C, I, V>(
+    query: BoxedSelectStatement<'a, ST, QS, Pg>,
+    column: C,
+    id: I,
+    ascending: bool,
+    key: Option<(V, i32)>,
+    direction: Direction,
+) -> BoxedSelectStatement<'a, ST, QS, Pg>
+where
+    C: Expression + Copy,
+    I: Expression<SqlType = Integer> + Copy,
+    V: AsExpression<C::SqlType> + Clone,
+    dsl::Or<dsl::Lt<C, V>, dsl::And<dsl::Eq<C, V>, dsl::Lt<I, i32>>>:
+        AppearsOnTable<QS, SqlType = Bool> + NonAggregate + QueryFragment<Pg> + 'a,
+    dsl::Or<dsl::Gt<C, V>, dsl::And<dsl::Eq<C, V>, dsl::Gt<I, i32>>>:
+        AppearsOnTable<QS, SqlType = Bool> + NonAggregate + QueryFragment<Pg> + 'a,
+    (dsl::Desc<C>, dsl::Desc<I>): AppearsOnTable<QS> + QueryFragment<Pg> + 'a,
+    (dsl::Asc<C>, dsl::Asc<I>): AppearsOnTable<QS> + QueryFragment<Pg> + 'a,
+{
+    let increasing = ascending == (direction == Direction::Forward);
+    if increasing {
+        let query = match key {
+            Some((v, i)) => query.filter(column.gt(v.clone()).or(column.eq(v).and(id.gt(i)))),
+            None => query,
+        };
+        query.order((column.asc(), id.asc()))
+    } else {
+        let query = match key {
+            Some((v, i)) => query.filter(column.lt(v.clone()).or(column.eq(v).and(id.lt(i)))),
+            None => query,
+        };
+        query.order((column.desc(), id.desc()))
+    }
+}
+
+pub struct KeysetLimit<K> {
+    cursor: Option<Cursor<K>>,
+    limit: i64,
+    with_total: bool,
+}
+
+pub fn keyset_limit<K>(cursor: Option<Cursor<K>>, limit: i64) -> KeysetLimit<K> {
+    KeysetLimit {
+        cursor,
+        limit,
+        with_total: true,
+    }
+}
+
+impl<K: Serialize> KeysetLimit<K> {
+    //Counting every matching row is the slow part on large tables, so it can be skipped
+    pub fn with_total(self, with_total: bool) -> Self {
+        KeysetLimit { with_total, ..self }
+    }
+
+    // `key_of` gives the key of a row, which must be unique and match the listing's order.
+    // `load` is given the key to start from (if any), the direction and the number of rows
+    // to fetch. It must only return rows strictly past the key, ordered in that direction.
+    // `count` is only run when the total was asked for.
+    pub fn load<T, KF, L, C>(self, key_of: KF, load: L, count: C) -> QueryResult<CursorResult<T>>
+    where
+        KF: Fn(&T) -> K,
+        L: FnOnce(Option<&K>, Direction, i64) -> QueryResult<Vec<T>>,
+        C: FnOnce() -> QueryResult<i64>,
+    {
+        let direction = match &self.cursor {
+            Some(c) => c.direction,
+            None => Direction::Forward,
+        };
+        //The extra row shows whether there is anything beyond this page
+        let mut results = load(
+            self.cursor.as_ref().map(|c| &c.key),
+            direction,
+            self.limit + 1,
+        )?;
+        let more = results.len() as i64 > self.limit;
+        results.truncate(self.limit as usize);
+        if direction == Direction::Backward {
+            results.reverse();
+        }
+
+        //Moving from a cursor means there were rows on the side it came from. An empty page
+        //has no rows to continue from, so the client has to start again from the first page
+        let (has_next, has_prev) = match direction {
+            Direction::Forward => (more, self.cursor.is_some()),
+            Direction::Backward => (true, more),
+        };
+        let cursor = |row: Option<&T>, direction: Direction| {
+            row.map(|r| {
+                Cursor {
+                    key: key_of(r),
+                    direction,
+                }
+                .encode()
+            })
+        };
+        let next = if has_next {
+            cursor(results.last(), Direction::Forward)
+        } else {
+            None
+        };
+        let prev = if has_prev {
+            cursor(results.first(), Direction::Backward)
+        } else {
+            None
+        };
+
+        let total = if self.with_total {
+            Some(count()?)
+        } else {
+            None
+        };
+        Ok(CursorResult {
+            results,
+            next,
+            prev,
+            total,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CursorResult<T> {
+    pub results: Vec<T>,
+    pub next: Option<String>,
+    pub prev: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+}
+
+impl<T> CursorResult<T> {
+    pub fn map<F, U>(self, func: F) -> CursorResult<U>
+    where
+        F: Fn(T) -> U,
+    {
+        CursorResult {
+            results: self.results.into_iter().map(func).collect(),
+            next: self.next,
+            prev: self.prev,
+            total: self.total,
+        }
+    }
+}
+
+//Either kind of page, for listings where the client chooses how to paginate
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum PageResult<T> {
+    Counted(CountedLimitResult<T>),
+    Cursor(CursorResult<T>),
+}
+
+impl<T> PageResult<T> {
+    pub fn map<F, U>(self, func: F) -> PageResult<U>
+    where
+        F: Fn(T) -> U,
+    {
+        match self {
+            PageResult::Counted(r) => PageResult::Counted(r.map(func)),
+            PageResult::Cursor(r) => PageResult::Cursor(r.map(func)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let t = Utc.timestamp(1_600_000_000, 123_456_000);
+        let cursor = Cursor {
+            key: (TimestampKey(t), 42),
+            direction: Direction::Backward,
+        };
+        let decoded = Cursor::<(TimestampKey, i32)>::decode(&cursor.encode()).unwrap();
+        assert_eq!((decoded.key.0).0, t);
+        assert_eq!(decoded.key.1, 42);
+        assert_eq!(decoded.direction, Direction::Backward);
+    }
+
+    #[test]
+    fn invalid_cursors_are_rejected() {
+        assert!(Cursor::<(TimestampKey, i32)>::decode("not a cursor").is_none());
+        let wrong_key = Cursor {
+            key: "text",
+            direction: Direction::Forward,
+        };
+        assert!(Cursor::<(TimestampKey, i32)>::decode(&wrong_key.encode()).is_none());
+    }
+
+    #[test]
+    fn empty_page_has_no_links() {
+        let cursor = Cursor {
+            key: 5,
+            direction: Direction::Forward,
+        };
+        let result = keyset_limit(Some(cursor), 10)
+            .with_total(false)
+            .load(|r: &i32| *r, |_, _, _| Ok(Vec::new()), || Ok(0))
+            .unwrap();
+        assert!(result.results.is_empty());
+        assert!(result.next.is_none());
+        assert!(result.prev.is_none());
+    }
+
+    #[test]
+    fn backward_page_is_in_listing_order() {
+        //The listing is 1 to 10 ascending, so moving back from 6 gives 4 and 5
+        let cursor = Cursor {
+            key: 6,
+            direction: Direction::Backward,
+        };
+        let result = keyset_limit(Some(cursor), 2)
+            .with_total(false)
+            .load(
+                |r: &i32| *r,
+                |key, direction, limit| {
+                    assert_eq!(direction, Direction::Backward);
+                    let key = *key.unwrap();
+                    Ok((1..key).rev().take(limit as usize).collect())
+                },
+                || Ok(10),
+            )
+            .unwrap();
+        assert_eq!(result.results, vec![4, 5]);
+        let prev = Cursor::<i32>::decode(result.prev.as_ref().unwrap()).unwrap();
+        assert_eq!((prev.key, prev.direction), (4, Direction::Backward));
+        let next = Cursor::<i32>::decode(result.next.as_ref().unwrap()).unwrap();
+        assert_eq!((next.key, next.direction), (5, Direction::Forward));
+    }
+
+    #[test]
+    fn timestamp_key_keeps_microseconds() {
+        for t in [
+            Utc.timestamp(1_600_000_000, 999_999_000),
+            Utc.timestamp(-1, 1_000),
+            Utc.timestamp(0, 0),
+        ]
+        .iter()
+        {
+            let json = serde_json::to_string(&TimestampKey(*t)).unwrap();
+            let decoded: TimestampKey = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded.0, *t);
+        }
+    }
+}
@@ -1,6 +1,29 @@
 use diesel::sql_function;
-use diesel::sql_types::{Integer, Text};
+use diesel::sql_types::{Integer, Nullable, Text, Timestamptz};
 
 sql_function!(fn strpos (string: Text, substring: Text) -> Integer);
 
 sql_function!(fn lower (string: Text) -> Text);
+
+sql_function!(fn coalesce (value: Nullable<Timestamptz>, default: Timestamptz) -> Timestamptz);
+
+// Escapes the LIKE wildcards in a string that should be matched literally
+pub fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_like_escapes_wildcards() {
+        assert_eq!(escape_like("plain text"), "plain text");
+        assert_eq!(escape_like("100%"), "100\\%");
+        assert_eq!(escape_like("a_b"), "a\\_b");
+        assert_eq!(escape_like("C:\\dir"), "C:\\\\dir");
+        assert_eq!(escape_like("\\%_"), "\\\\\\%\\_");
+    }
+}
@@ -1,2 +1,3 @@
+pub mod cursor;
 pub mod functions;
 pub mod limit;
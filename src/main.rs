@@ -4,6 +4,7 @@ mod models;
 mod schema;
 mod settings;
 mod state;
+mod tasks;
 
 #[macro_use]
 extern crate diesel;
@@ -54,6 +55,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let state = AppState::new(settings, pool);
 
     embedded_migrations::run_with_output(&state.new_connection(), &mut std::io::stdout())?;
+    tasks::spawn_session_sweeper(state.clone());
+    tasks::spawn_user_purger(state.clone());
 
     let address = format!("0.0.0.0:{}", state.settings.app.port);
     println!("Starting server on port {}", state.settings.app.port);
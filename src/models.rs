@@ -8,7 +8,9 @@ use chrono::offset::Utc;
 use chrono::DateTime;
 
 use bigdecimal::BigDecimal;
+use ipnetwork::IpNetwork;
 use serde::Serialize;
+use serde_json::Value;
 
 // https://github.com/diesel-rs/diesel/blob/master/guide_drafts/trait_derives.md#identifiable
 // Note that Identifiable assumes: #[primary_key(id)]
@@ -20,7 +22,16 @@ pub struct User {
     pub name: String,
     pub email: String,
     pub password_hash: Option<String>,
-    pub password_reset_token: Option<String>,
+    pub role: String,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub failed_login_attempts: i32,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub notify_new_login: bool,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub created: DateTime<Utc>,
+    pub last_login: Option<DateTime<Utc>>,
+    pub totp_last_step: Option<i64>,
 }
 
 #[derive(Debug, Insertable)]
@@ -29,7 +40,7 @@ pub struct NewUser {
     pub name: String,
     pub email: String,
     pub password_hash: Option<String>,
-    pub password_reset_token: Option<String>,
+    pub role: String,
 }
 
 #[derive(Debug, Queryable, Identifiable, Associations)]
@@ -37,20 +48,143 @@ pub struct NewUser {
 pub struct Session {
     pub id: i32,
     pub user_id: i32,
-    pub token: String,
+    pub token_hash: String,
     pub created: DateTime<Utc>,
     pub last_used: DateTime<Utc>,
-    pub last_ip: Vec<u8>,
     pub user_agent: String,
+    pub name: Option<String>,
+    pub last_ip: IpNetwork,
+    pub revoke_token_hash: Option<String>,
 }
 
 #[derive(Debug, Insertable)]
 #[table_name = "sessions"]
 pub struct NewSession {
     pub user_id: i32,
-    pub token: String,
-    pub last_ip: Vec<u8>,
+    pub token_hash: String,
+    pub last_ip: IpNetwork,
     pub user_agent: String,
+    pub revoke_token_hash: Option<String>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct SecurityEvent {
+    pub id: i32,
+    pub user_id: i32,
+    pub kind: String,
+    pub success: bool,
+    pub ip: Option<IpNetwork>,
+    pub user_agent: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "security_events"]
+pub struct NewSecurityEvent {
+    pub user_id: i32,
+    pub kind: String,
+    pub success: bool,
+    pub ip: Option<IpNetwork>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct EmailChange {
+    pub id: i32,
+    pub user_id: i32,
+    pub new_email: String,
+    pub token_hash: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+pub struct Invitation {
+    pub id: i32,
+    pub email: String,
+    pub role: String,
+    pub invited_by: Option<i32>,
+    pub token_hash: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct LoginChallenge {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct MagicLinkToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub used: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct PasswordResetToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub used: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct WebauthnChallenge {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub kind: String,
+    pub state: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct WebauthnCredential {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub credential_id: String,
+    pub credential: String,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name = "audit_log"]
+pub struct AuditLogEntry {
+    pub id: i32,
+    pub actor_id: Option<i32>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: i32,
+    pub diff: Value,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "audit_log"]
+pub struct NewAuditLogEntry {
+    pub actor_id: Option<i32>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: i32,
+    pub diff: Value,
 }
 
 #[derive(Queryable, Debug, Identifiable, Serialize)]
@@ -1,3 +1,26 @@
+table! {
+    audit_log (id) {
+        id -> Int4,
+        actor_id -> Nullable<Int4>,
+        action -> Varchar,
+        target_type -> Varchar,
+        target_id -> Int4,
+        diff -> Jsonb,
+        created -> Timestamptz,
+    }
+}
+
+table! {
+    email_changes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        new_email -> Varchar,
+        token_hash -> Varchar,
+        created -> Timestamptz,
+        expires -> Timestamptz,
+    }
+}
+
 table! {
     files (id) {
         id -> Int4,
@@ -26,15 +49,80 @@ table! {
     }
 }
 
+table! {
+    invitations (id) {
+        id -> Int4,
+        email -> Varchar,
+        role -> Varchar,
+        invited_by -> Nullable<Int4>,
+        token_hash -> Varchar,
+        created -> Timestamptz,
+        expires -> Timestamptz,
+    }
+}
+
+table! {
+    login_challenges (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        created -> Timestamptz,
+    }
+}
+
+table! {
+    magic_link_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        created -> Timestamptz,
+        expires -> Timestamptz,
+        used -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    password_reset_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        created -> Timestamptz,
+        expires -> Timestamptz,
+        used -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Varchar,
+    }
+}
+
+table! {
+    security_events (id) {
+        id -> Int4,
+        user_id -> Int4,
+        kind -> Varchar,
+        success -> Bool,
+        ip -> Nullable<Inet>,
+        user_agent -> Nullable<Varchar>,
+        created -> Timestamptz,
+    }
+}
+
 table! {
     sessions (id) {
         id -> Int4,
         user_id -> Int4,
-        token -> Varchar,
+        token_hash -> Varchar,
         created -> Timestamptz,
         last_used -> Timestamptz,
-        last_ip -> Bytea,
         user_agent -> Varchar,
+        name -> Nullable<Varchar>,
+        last_ip -> Inet,
+        revoke_token_hash -> Nullable<Varchar>,
     }
 }
 
@@ -44,13 +132,71 @@ table! {
         name -> Varchar,
         email -> Varchar,
         password_hash -> Nullable<Varchar>,
-        password_reset_token -> Nullable<Varchar>,
+        role -> Varchar,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled -> Bool,
+        failed_login_attempts -> Int4,
+        locked_until -> Nullable<Timestamptz>,
+        notify_new_login -> Bool,
+        deleted_at -> Nullable<Timestamptz>,
+        created -> Timestamptz,
+        last_login -> Nullable<Timestamptz>,
+        totp_last_step -> Nullable<Int8>,
+    }
+}
+
+table! {
+    webauthn_challenges (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        kind -> Varchar,
+        state -> Text,
+        created -> Timestamptz,
+    }
+}
+
+table! {
+    webauthn_credentials (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        credential_id -> Varchar,
+        credential -> Text,
+        created -> Timestamptz,
+        last_used -> Nullable<Timestamptz>,
     }
 }
 
+joinable!(audit_log -> users (actor_id));
+joinable!(email_changes -> users (user_id));
 joinable!(gallery_files -> files (file_id));
 joinable!(gallery_files -> gallery_items (item_id));
 joinable!(gallery_items -> files (original_file_id));
+joinable!(invitations -> users (invited_by));
+joinable!(login_challenges -> users (user_id));
+joinable!(magic_link_tokens -> users (user_id));
+joinable!(password_reset_tokens -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(security_events -> users (user_id));
 joinable!(sessions -> users (user_id));
+joinable!(webauthn_challenges -> users (user_id));
+joinable!(webauthn_credentials -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(files, gallery_files, gallery_items, sessions, users,);
+allow_tables_to_appear_in_same_query!(
+    audit_log,
+    email_changes,
+    files,
+    gallery_files,
+    gallery_items,
+    invitations,
+    login_challenges,
+    magic_link_tokens,
+    password_reset_tokens,
+    recovery_codes,
+    security_events,
+    sessions,
+    users,
+    webauthn_challenges,
+    webauthn_credentials,
+);
@@ -1,4 +1,6 @@
+use chrono::{DateTime, Duration, Utc};
 use config::{Config, ConfigError, Environment, File, FileFormat};
+use ipnetwork::IpNetwork;
 use lettre::smtp::authentication::Credentials;
 use lettre::{smtp, ClientSecurity, ClientTlsParameters, SmtpClient, SmtpTransport};
 use native_tls::TlsConnector;
@@ -15,6 +17,8 @@ pub struct App {
     pub contact_mailbox: String,
     pub password_reset_url: Url,
     pub api_url: Url,
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNetwork>,
 }
 
 fn validate_folder_path(path: &Box<Path>) -> Result<(), ValidationError> {
@@ -22,7 +26,7 @@ fn validate_folder_path(path: &Box<Path>) -> Result<(), ValidationError> {
     Ok(())
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Mailer {
     host: String,
     port: u16,
@@ -39,12 +43,253 @@ pub struct Database {
     name: String,
 }
 
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct Sessions {
+    #[validate(range(min = 1))]
+    pub idle_timeout_days: i64,
+    #[validate(range(min = 1))]
+    pub max_lifetime_days: i64,
+    #[validate(range(min = 1))]
+    pub sweep_interval_minutes: u64,
+    #[validate(range(min = 1))]
+    pub login_challenge_minutes: i64,
+    // New login emails are only sent when there is a page to revoke the session from
+    pub revoke_url: Option<Url>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions {
+            idle_timeout_days: 30,
+            max_lifetime_days: 365,
+            sweep_interval_minutes: 60,
+            login_challenge_minutes: 5,
+            revoke_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct Lockout {
+    #[validate(range(min = 1))]
+    pub max_failed_attempts: i32,
+    #[validate(range(min = 1))]
+    pub lockout_minutes: i64,
+    #[validate(range(min = 1))]
+    pub max_lockout_minutes: i64,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Lockout {
+            max_failed_attempts: 5,
+            lockout_minutes: 15,
+            max_lockout_minutes: 24 * 60,
+        }
+    }
+}
+
+// Email addresses can't be changed unless the url of the page to confirm from is set
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct EmailChange {
+    pub url: Option<Url>,
+    #[validate(range(min = 1))]
+    pub expiry_hours: i64,
+}
+
+impl Default for EmailChange {
+    fn default() -> Self {
+        EmailChange {
+            url: None,
+            expiry_hours: 24,
+        }
+    }
+}
+
+// Users can't be invited unless the url of the page to accept from is set. Lapsed invitations
+// are kept for a while so that they can still be resent
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct Invitations {
+    pub url: Option<Url>,
+    #[validate(range(min = 1))]
+    pub expiry_hours: i64,
+    #[validate(range(min = 0))]
+    pub purge_after_days: i64,
+}
+
+impl Default for Invitations {
+    fn default() -> Self {
+        Invitations {
+            url: None,
+            expiry_hours: 72,
+            purge_after_days: 30,
+        }
+    }
+}
+
+// Deleted users can be restored until they are purged
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct DeletedUsers {
+    #[validate(range(min = 0))]
+    pub purge_after_days: i64,
+    #[validate(range(min = 1))]
+    pub purge_interval_minutes: u64,
+}
+
+impl Default for DeletedUsers {
+    fn default() -> Self {
+        DeletedUsers {
+            purge_after_days: 30,
+            purge_interval_minutes: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct PasswordReset {
+    #[validate(range(min = 1))]
+    pub expiry_hours: i64,
+    pub notify_unknown_email: bool,
+}
+
+impl Default for PasswordReset {
+    fn default() -> Self {
+        PasswordReset {
+            expiry_hours: 24,
+            notify_unknown_email: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+#[validate(schema(function = "validate_length_range"))]
+pub struct PasswordPolicy {
+    #[validate(range(min = 8))]
+    pub min_length: usize,
+    #[validate(range(min = 16))]
+    pub max_length: usize,
+    pub disallow_personal_info: bool,
+    #[validate(custom = "validate_existing_folder")]
+    pub breached_passwords_folder: Option<Box<Path>>,
+}
+
+//Otherwise no password could ever be accepted
+fn validate_length_range(policy: &PasswordPolicy) -> Result<(), ValidationError> {
+    if policy.min_length > policy.max_length {
+        return Err(ValidationError::new("min_length_exceeds_max_length"));
+    }
+    Ok(())
+}
+
+//Unlike the storage folder this is never created, as it should already hold the downloaded lists
+fn validate_existing_folder(path: &Box<Path>) -> Result<(), ValidationError> {
+    if !path.is_dir() {
+        return Err(ValidationError::new("folder_not_found"));
+    }
+    Ok(())
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 16,
+            max_length: 255,
+            disallow_personal_info: true,
+            breached_passwords_folder: None,
+        }
+    }
+}
+
+// Login by email link is disabled unless the url is set
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct MagicLink {
+    pub url: Option<Url>,
+    #[validate(range(min = 1))]
+    pub expiry_minutes: i64,
+}
+
+impl Default for MagicLink {
+    fn default() -> Self {
+        MagicLink {
+            url: None,
+            expiry_minutes: 15,
+        }
+    }
+}
+
+// Passkey login is disabled unless the relying party is configured
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebAuthn {
+    pub rp_id: String,
+    pub rp_name: String,
+    pub origin: Url,
+}
+
+// Argon2id parameters, existing hashes are upgraded on login when these change
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct PasswordHashing {
+    #[validate(range(min = 8192))]
+    pub memory_kib: u32,
+    #[validate(range(min = 1))]
+    pub iterations: u32,
+    #[validate(range(min = 1))]
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashing {
+    fn default() -> Self {
+        PasswordHashing {
+            memory_kib: 19456,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Validate)]
 pub struct Settings {
     #[validate]
     pub app: App,
     pub database: Database,
     pub mailer: Mailer,
+    #[serde(default)]
+    #[validate]
+    pub sessions: Sessions,
+    #[serde(default)]
+    #[validate]
+    pub lockout: Lockout,
+    #[serde(default)]
+    #[validate]
+    pub invitations: Invitations,
+    #[serde(default)]
+    #[validate]
+    pub email_change: EmailChange,
+    #[serde(default)]
+    #[validate]
+    pub deleted_users: DeletedUsers,
+    #[serde(default)]
+    #[validate]
+    pub password_reset: PasswordReset,
+    #[serde(default)]
+    #[validate]
+    pub password_policy: PasswordPolicy,
+    #[serde(default)]
+    #[validate]
+    pub password_hashing: PasswordHashing,
+    #[serde(default)]
+    #[validate]
+    pub magic_link: MagicLink,
+    #[serde(default)]
+    pub webauthn: Option<WebAuthn>,
 }
 
 impl Settings {
@@ -73,6 +318,52 @@ impl Database {
     }
 }
 
+impl Sessions {
+    // Sessions last used before this time have expired
+    pub fn idle_cutoff(&self) -> DateTime<Utc> {
+        Utc::now() - Duration::days(self.idle_timeout_days)
+    }
+
+    // Sessions created before this time have expired
+    pub fn lifetime_cutoff(&self) -> DateTime<Utc> {
+        Utc::now() - Duration::days(self.max_lifetime_days)
+    }
+
+    // Two factor login challenges created before this time have expired
+    pub fn login_challenge_cutoff(&self) -> DateTime<Utc> {
+        Utc::now() - Duration::minutes(self.login_challenge_minutes)
+    }
+}
+
+impl DeletedUsers {
+    // Users deleted before this time are removed permanently
+    pub fn purge_cutoff(&self) -> DateTime<Utc> {
+        Utc::now() - Duration::days(self.purge_after_days)
+    }
+}
+
+impl Invitations {
+    // Invitations that expired before this time are removed
+    pub fn purge_cutoff(&self) -> DateTime<Utc> {
+        Utc::now() - Duration::days(self.purge_after_days)
+    }
+}
+
+impl Lockout {
+    // Each further batch of failures doubles the lockout, up to the maximum
+    pub fn lockout_duration(&self, failed_attempts: i32) -> Option<Duration> {
+        if failed_attempts < self.max_failed_attempts {
+            return None;
+        }
+        let lockouts = (failed_attempts / self.max_failed_attempts).min(32) as u32;
+        let minutes = self
+            .lockout_minutes
+            .saturating_mul(2i64.saturating_pow(lockouts - 1))
+            .min(self.max_lockout_minutes);
+        Some(Duration::minutes(minutes))
+    }
+}
+
 impl Mailer {
     pub fn smtp_transport(&self) -> Result<SmtpTransport, smtp::error::Error> {
         let connector = TlsConnector::new().unwrap();
@@ -2,6 +2,8 @@ use crate::settings::Settings;
 use diesel::prelude::*;
 use diesel::r2d2;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
+use rand::distributions::Standard;
+use rand::Rng;
 use std::sync::Arc;
 
 pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
@@ -10,6 +12,8 @@ pub type Connection = PooledConnection<ConnectionManager<PgConnection>>;
 #[derive(Clone)]
 pub struct AppState {
     pub settings: Arc<Settings>,
+    // Random for each run, for stand-in responses that mustn't be predictable
+    pub decoy_key: Arc<Vec<u8>>,
     pool: Pool,
 }
 
@@ -17,6 +21,7 @@ impl AppState {
     pub fn new(settings: Settings, pool: Pool) -> Self {
         AppState {
             settings: Arc::new(settings),
+            decoy_key: Arc::new(rand::thread_rng().sample_iter(&Standard).take(32).collect()),
             pool,
         }
     }
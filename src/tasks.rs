@@ -0,0 +1,111 @@
+use crate::state::AppState;
+use actix_web::web;
+use diesel::prelude::*;
+use std::time::Duration;
+
+// Periodically delete sessions that have passed their idle timeout or maximum lifetime,
+// any unused login challenges or email changes that have expired, and invitations that
+// lapsed longer ago than the purge delay
+pub fn spawn_session_sweeper(state: AppState) {
+    let period = Duration::from_secs(state.settings.sessions.sweep_interval_minutes * 60);
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            let state = state.clone();
+            let result = web::block(move || {
+                use crate::schema::sessions::dsl as S;
+                let db = state.new_connection();
+                let expiry = &state.settings.sessions;
+                let count = diesel::delete(
+                    S::sessions.filter(
+                        S::last_used
+                            .lt(expiry.idle_cutoff())
+                            .or(S::created.lt(expiry.lifetime_cutoff())),
+                    ),
+                )
+                .execute(&db)?;
+
+                use crate::schema::login_challenges::dsl as LC;
+                diesel::delete(
+                    LC::login_challenges.filter(LC::created.lt(expiry.login_challenge_cutoff())),
+                )
+                .execute(&db)?;
+
+                use crate::schema::webauthn_challenges::dsl as WCh;
+                diesel::delete(
+                    WCh::webauthn_challenges
+                        .filter(WCh::created.lt(expiry.login_challenge_cutoff())),
+                )
+                .execute(&db)?;
+
+                use crate::schema::email_changes::dsl as EC;
+                diesel::delete(EC::email_changes.filter(EC::expires.lt(diesel::dsl::now)))
+                    .execute(&db)?;
+
+                use crate::schema::invitations::dsl as I;
+                let cutoff = state.settings.invitations.purge_cutoff();
+                diesel::delete(I::invitations.filter(I::expires.lt(cutoff))).execute(&db)?;
+                Ok::<_, diesel::result::Error>(count)
+            })
+            .await;
+            match result {
+                Ok(count) => log::info!("Deleted {} expired sessions", count),
+                Err(e) => log::warn!("Unable to delete expired sessions: {}", e),
+            }
+        }
+    });
+}
+
+// Periodically remove users who were deleted longer ago than the purge delay, along with
+// everything that references them
+pub fn spawn_user_purger(state: AppState) {
+    let period = Duration::from_secs(state.settings.deleted_users.purge_interval_minutes * 60);
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            let state = state.clone();
+            let result = web::block(move || {
+                use crate::schema::email_changes::dsl as EC;
+                use crate::schema::login_challenges::dsl as LC;
+                use crate::schema::magic_link_tokens::dsl as M;
+                use crate::schema::password_reset_tokens::dsl as P;
+                use crate::schema::recovery_codes::dsl as RC;
+                use crate::schema::security_events::dsl as SE;
+                use crate::schema::sessions::dsl as S;
+                use crate::schema::users::dsl as U;
+                use crate::schema::webauthn_challenges::dsl as WCh;
+                use crate::schema::webauthn_credentials::dsl as WCr;
+                let db = state.new_connection();
+                let cutoff = state.settings.deleted_users.purge_cutoff();
+                let purged = || U::users.select(U::id).filter(U::deleted_at.lt(cutoff));
+                db.transaction::<_, diesel::result::Error, _>(|| {
+                    diesel::delete(S::sessions.filter(S::user_id.eq_any(purged()))).execute(&db)?;
+                    diesel::delete(LC::login_challenges.filter(LC::user_id.eq_any(purged())))
+                        .execute(&db)?;
+                    diesel::delete(EC::email_changes.filter(EC::user_id.eq_any(purged())))
+                        .execute(&db)?;
+                    diesel::delete(M::magic_link_tokens.filter(M::user_id.eq_any(purged())))
+                        .execute(&db)?;
+                    diesel::delete(RC::recovery_codes.filter(RC::user_id.eq_any(purged())))
+                        .execute(&db)?;
+                    diesel::delete(P::password_reset_tokens.filter(P::user_id.eq_any(purged())))
+                        .execute(&db)?;
+                    diesel::delete(WCh::webauthn_challenges.filter(WCh::user_id.eq_any(purged())))
+                        .execute(&db)?;
+                    diesel::delete(WCr::webauthn_credentials.filter(WCr::user_id.eq_any(purged())))
+                        .execute(&db)?;
+                    diesel::delete(SE::security_events.filter(SE::user_id.eq_any(purged())))
+                        .execute(&db)?;
+                    diesel::delete(U::users.filter(U::deleted_at.lt(cutoff))).execute(&db)
+                })
+            })
+            .await;
+            match result {
+                Ok(count) => log::info!("Purged {} deleted users", count),
+                Err(e) => log::warn!("Unable to purge deleted users: {}", e),
+            }
+        }
+    });
+}